@@ -0,0 +1,338 @@
+use crate::*;
+use near_sdk::{assert_one_yocto, Gas, PromiseOrValue, PromiseResult};
+
+const GAS_FOR_RESOLVE_CLAIM_PER_PAYOUT: Gas = 5_000_000_000_000;
+/// The gas for claiming one account of a batch, not counting the resolve.
+const GAS_FOR_BATCH_CLAIM: Gas = 5_000_000_000_000;
+
+/// The maximum number of recipients in a reward split.
+pub const MAX_REWARD_SPLIT_RECIPIENTS: usize = 5;
+
+/// A share of the NEAR claims in basis points.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RewardSplit {
+    pub account_id: AccountId,
+    pub bps: u16,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ClaimPayout {
+    pub receiver_id: AccountId,
+    pub amount: U128,
+}
+
+#[ext_contract(ext_claim_self)]
+trait ClaimResolver {
+    fn resolve_claim(&mut self, account_id: AccountId, payouts: Vec<ClaimPayout>) -> U128;
+}
+
+#[near_bindgen]
+impl Farm {
+    /// Sends the NEAR earned by the predecessor to its reward split, its reward beneficiary, or
+    /// to the predecessor if there are none. The claim is rolled back if the transfer fails.
+    pub fn claim_near(&mut self) -> PromiseOrValue<U128> {
+        self.assert_claims_not_paused();
+        let account_id = env::predecessor_account_id();
+        self.internal_claim_near(&account_id, None)
+    }
+
+    /// Sends the NEAR earned by the predecessor to `receiver_id`. The claim is still recorded on
+    /// the predecessor's account.
+    #[payable]
+    pub fn claim_near_to(&mut self, receiver_id: ValidAccountId) -> PromiseOrValue<U128> {
+        assert_one_yocto();
+        self.assert_claims_not_paused();
+        let account_id = env::predecessor_account_id();
+        self.internal_claim_near(&account_id, Some(receiver_id.as_ref()))
+    }
+
+    /// Claims the NEAR earned by `account_id` on its behalf. Anyone can call it, because the NEAR
+    /// always goes to the reward split or the reward beneficiary of the account.
+    pub fn claim_near_for(&mut self, account_id: ValidAccountId) -> PromiseOrValue<U128> {
+        self.assert_claims_not_paused();
+        let account = self
+            .get_internal_account(account_id.as_ref())
+            .1
+            .expect("The account is not registered");
+        if account.reward_split.is_empty() && account.reward_beneficiary.is_none() {
+            env::panic(b"The account has no reward beneficiary");
+        }
+        self.internal_claim_near(account_id.as_ref(), None)
+    }
+
+    /// Sets the account that receives all future NEAR claims of the predecessor.
+    /// `None` sends the claims back to the predecessor.
+    /// The storage deposit of the account should cover the beneficiary.
+    #[payable]
+    pub fn set_reward_beneficiary(&mut self, beneficiary_id: Option<ValidAccountId>) {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let (account_id_hash, mut account) = self.get_mut_account(&account_id);
+        account.reward_beneficiary = beneficiary_id.map(|id| id.into());
+        self.save_account(&account_id_hash, &mut account);
+        emit_farm_event(
+            "reward_beneficiary_update",
+            json!({
+                "account_id": account_id,
+                "beneficiary_id": account.reward_beneficiary,
+            }),
+        );
+    }
+
+    pub fn get_reward_beneficiary(&self, account_id: ValidAccountId) -> Option<AccountId> {
+        self.get_internal_account(account_id.as_ref())
+            .1
+            .and_then(|account| account.reward_beneficiary)
+    }
+
+    /// Splits all future NEAR claims of the predecessor between the given accounts. The shares
+    /// should sum to 10,000 basis points. An empty split turns it off.
+    /// The split takes precedence over the reward beneficiary.
+    /// The storage deposit of the account should cover the split.
+    #[payable]
+    pub fn set_reward_split(&mut self, reward_split: Vec<RewardSplit>) {
+        assert_one_yocto();
+        assert_valid_reward_split(&reward_split);
+        let account_id = env::predecessor_account_id();
+        let (account_id_hash, mut account) = self.get_mut_account(&account_id);
+        account.reward_split = reward_split;
+        self.save_account(&account_id_hash, &mut account);
+        emit_farm_event(
+            "reward_split_update",
+            json!({
+                "account_id": account_id,
+                "reward_split": account.reward_split,
+            }),
+        );
+    }
+
+    pub fn get_reward_split(&self, account_id: ValidAccountId) -> Vec<RewardSplit> {
+        self.get_internal_account(account_id.as_ref())
+            .1
+            .map(|account| account.reward_split)
+            .unwrap_or_default()
+    }
+
+    /// Opts the predecessor in to `claim_for_accounts`. The NEAR is claimed on its behalf once the
+    /// balance reaches `min_amount`. `None` opts out.
+    #[payable]
+    pub fn set_auto_claim_threshold(&mut self, min_amount: Option<U128>) {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let (account_id_hash, mut account) = self.get_mut_account(&account_id);
+        account.auto_claim_threshold = min_amount.map(|amount| amount.into());
+        self.save_account(&account_id_hash, &mut account);
+        emit_farm_event(
+            "auto_claim_threshold_update",
+            json!({
+                "account_id": account_id,
+                "min_amount": min_amount,
+            }),
+        );
+    }
+
+    /// Claims the NEAR of the listed accounts that opted in with `set_auto_claim_threshold` and
+    /// earned at least their threshold. Anyone can call it, because the NEAR always goes to the
+    /// account itself, its reward split or its reward beneficiary.
+    /// Stops when the remaining gas is not enough for the next account.
+    /// Returns the number of processed accounts, so the rest can be passed to the next call.
+    pub fn claim_for_accounts(&mut self, account_ids: Vec<ValidAccountId>) -> u32 {
+        self.assert_claims_not_paused();
+        let mut num_processed = 0;
+        for account_id in account_ids {
+            let account = self.get_internal_account(account_id.as_ref()).1;
+            if let Some(mut account) = account {
+                let required_gas = claim_near_gas(&account);
+                if env::prepaid_gas() - env::used_gas() < required_gas {
+                    break;
+                }
+                self.touch(&mut account);
+                if let Some(min_amount) = account.auto_claim_threshold {
+                    if account.near_balance > 0 && account.near_balance >= min_amount {
+                        self.internal_claim_near(account_id.as_ref(), None);
+                    }
+                }
+            }
+            num_processed += 1;
+        }
+        num_processed
+    }
+
+    /// Restores the NEAR balance and the claimed counters for every failed payout.
+    /// If the account has unregistered in the meantime, the NEAR goes to the undistributed pool.
+    /// Returns the amount of NEAR that was actually claimed.
+    #[private]
+    pub fn resolve_claim(&mut self, account_id: AccountId, payouts: Vec<ClaimPayout>) -> U128 {
+        let mut claimed_amount = 0;
+        let mut failed_amount = 0;
+        for (index, payout) in payouts.into_iter().enumerate() {
+            let amount: Balance = payout.amount.into();
+            match env::promise_result(index as u64) {
+                PromiseResult::NotReady => unreachable!(),
+                PromiseResult::Successful(_) => {
+                    claimed_amount += amount;
+                    emit_farm_event(
+                        "near_claim",
+                        json!({
+                            "account_id": account_id,
+                            "receiver_id": payout.receiver_id,
+                            "amount": U128(amount),
+                        }),
+                    );
+                }
+                PromiseResult::Failed => {
+                    failed_amount += amount;
+                    emit_farm_event(
+                        "near_claim_failed",
+                        json!({
+                            "account_id": account_id,
+                            "receiver_id": payout.receiver_id,
+                            "amount": U128(amount),
+                        }),
+                    );
+                }
+            }
+        }
+        if failed_amount > 0 {
+            self.restore_claim(&account_id, failed_amount);
+        }
+        claimed_amount.into()
+    }
+}
+
+impl Farm {
+    /// Moves the whole NEAR balance of the touched account to the claimed counters and saves the
+    /// account. Returns the claimed amount.
+    pub(crate) fn take_claim(
+        &mut self,
+        account_id_hash: &ShortAccountHash,
+        account: &mut Account,
+    ) -> Balance {
+        let amount = account.near_balance;
+        if amount > 0 {
+            account.near_balance = 0;
+            account.near_claimed += amount;
+            self.save_account(account_id_hash, account);
+            self.total_near_claimed += amount;
+        }
+        amount
+    }
+
+    /// Rolls back the claim of `amount`. If the account has unregistered in the meantime, the NEAR
+    /// goes to the undistributed pool.
+    pub(crate) fn restore_claim(&mut self, account_id: &AccountId, amount: Balance) {
+        self.total_near_claimed -= amount;
+        if self.get_internal_account(account_id).1.is_some() {
+            let (account_id_hash, mut account) = self.get_mut_account(account_id);
+            account.near_balance += amount;
+            account.near_claimed -= amount;
+            self.save_account(&account_id_hash, &mut account);
+        } else {
+            self.total_near_distributed -= amount;
+            self.undistributed_near += amount;
+        }
+    }
+
+    /// Claims the NEAR balance of the account and sends it to the receiver. Without the receiver
+    /// the NEAR goes to the reward split, the reward beneficiary or the account itself.
+    pub(crate) fn internal_claim_near(
+        &mut self,
+        account_id: &AccountId,
+        receiver_id: Option<&AccountId>,
+    ) -> PromiseOrValue<U128> {
+        let (account_id_hash, mut account) = self.get_mut_account(account_id);
+        let amount = self.take_claim(&account_id_hash, &mut account);
+        if amount == 0 {
+            return PromiseOrValue::Value(0.into());
+        }
+        let payouts = if let Some(receiver_id) = receiver_id {
+            vec![(receiver_id.clone(), amount)]
+        } else if !account.reward_split.is_empty() {
+            split_amount(&account.reward_split, amount)
+        } else {
+            let receiver_id = account
+                .reward_beneficiary
+                .clone()
+                .unwrap_or_else(|| account_id.clone());
+            vec![(receiver_id, amount)]
+        };
+
+        let transfers = payouts
+            .iter()
+            .map(|(receiver_id, amount)| Promise::new(receiver_id.clone()).transfer(*amount))
+            .reduce(|transfers, transfer| transfers.and(transfer))
+            .unwrap();
+        let resolve_gas = GAS_FOR_RESOLVE_CLAIM_PER_PAYOUT * payouts.len() as Gas;
+        let payouts = payouts
+            .into_iter()
+            .map(|(receiver_id, amount)| ClaimPayout {
+                receiver_id,
+                amount: amount.into(),
+            })
+            .collect();
+        transfers
+            .then(ext_claim_self::resolve_claim(
+                account_id.clone(),
+                payouts,
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                resolve_gas,
+            ))
+            .into()
+    }
+}
+
+/// Returns the gas for claiming the NEAR of the account, including the resolve of its payouts.
+pub(crate) fn claim_near_gas(account: &Account) -> Gas {
+    GAS_FOR_BATCH_CLAIM
+        + GAS_FOR_RESOLVE_CLAIM_PER_PAYOUT * account.reward_split.len().max(1) as Gas
+}
+
+/// Splits the amount by the shares. The rounding remainder goes to the last recipient.
+fn split_amount(reward_split: &[RewardSplit], amount: Balance) -> Vec<(AccountId, Balance)> {
+    let mut remaining_amount = amount;
+    let mut payouts: Vec<(AccountId, Balance)> = reward_split
+        .iter()
+        .map(|split| {
+            let split_amount = (U256::from(amount) * U256::from(split.bps)
+                / U256::from(MAX_BASIS_POINTS))
+            .as_u128();
+            remaining_amount -= split_amount;
+            (split.account_id.clone(), split_amount)
+        })
+        .collect();
+    payouts.last_mut().unwrap().1 += remaining_amount;
+    // Zero transfers are skipped.
+    payouts.retain(|(_, amount)| *amount > 0);
+    payouts
+}
+
+fn assert_valid_reward_split(reward_split: &[RewardSplit]) {
+    if reward_split.is_empty() {
+        return;
+    }
+    if reward_split.len() > MAX_REWARD_SPLIT_RECIPIENTS {
+        env::panic(b"Too many reward split recipients");
+    }
+    let mut total_bps: u32 = 0;
+    for (index, split) in reward_split.iter().enumerate() {
+        if !env::is_valid_account_id(split.account_id.as_bytes()) {
+            env::panic(b"Invalid reward split account ID");
+        }
+        if split.bps == 0 {
+            env::panic(b"Reward split shares should be positive");
+        }
+        if reward_split[..index]
+            .iter()
+            .any(|other| other.account_id == split.account_id)
+        {
+            env::panic(b"Duplicate reward split recipient");
+        }
+        total_bps += split.bps as u32;
+    }
+    if total_bps != MAX_BASIS_POINTS as u32 {
+        env::panic(b"Reward split shares should sum to 10,000 basis points");
+    }
+}
@@ -0,0 +1,233 @@
+//! Claiming NEAR with a call of the receiver contract, similar to `ft_transfer_call`.
+//! The receiver gets the claimed NEAR attached to `on_near_claimed`. To return the unused NEAR,
+//! the receiver returns the promise of `return_claimed_near` on the farm with the claim ID and the
+//! unused NEAR attached, so it's executed before the resolve. The resolver puts back only the NEAR
+//! that was actually returned for the claim.
+use crate::*;
+use near_sdk::json_types::U64;
+use near_sdk::{assert_one_yocto, Gas, PromiseOrValue, PromiseResult};
+
+const GAS_FOR_RESOLVE_CLAIM_CALL: Gas = 10_000_000_000_000;
+const GAS_FOR_CLAIM_NEAR_CALL: Gas = 25_000_000_000_000 + GAS_FOR_RESOLVE_CLAIM_CALL;
+
+/// The claim of `claim_near_call` that is not resolved yet.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ClaimCall {
+    pub receiver_id: AccountId,
+    /// The unused NEAR returned by the receiver with `return_claimed_near`.
+    pub returned_amount: Balance,
+}
+
+#[ext_contract(ext_near_claim_receiver)]
+pub trait NearClaimReceiver {
+    /// Receives the NEAR claimed by `sender_id` as the attached deposit.
+    /// Returns the amount of unused NEAR, usually as the promise of `return_claimed_near` with
+    /// the `claim_id`.
+    fn on_near_claimed(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        claim_id: U64,
+        msg: String,
+    ) -> PromiseOrValue<U128>;
+}
+
+#[ext_contract(ext_claim_call_self)]
+trait ClaimCallResolver {
+    fn resolve_claim_call(
+        &mut self,
+        account_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        claim_id: U64,
+    ) -> U128;
+}
+
+#[near_bindgen]
+impl Farm {
+    /// Claims the NEAR earned by the predecessor and attaches it to a call of `on_near_claimed`
+    /// on `receiver_id`. The unused NEAR returned by the receiver goes back to the predecessor's
+    /// NEAR balance.
+    /// Returns the amount of NEAR used by the receiver.
+    #[payable]
+    pub fn claim_near_call(
+        &mut self,
+        receiver_id: ValidAccountId,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        assert_one_yocto();
+        self.assert_claims_not_paused();
+        let account_id = env::predecessor_account_id();
+        let (account_id_hash, mut account) = self.get_mut_account(&account_id);
+        let amount = self.take_claim(&account_id_hash, &mut account);
+        if amount == 0 {
+            return PromiseOrValue::Value(0.into());
+        }
+        let claim_id = self.next_claim_call_id;
+        self.next_claim_call_id += 1;
+        self.claim_calls.insert(
+            &claim_id,
+            &ClaimCall {
+                receiver_id: receiver_id.clone().into(),
+                returned_amount: 0,
+            },
+        );
+
+        ext_near_claim_receiver::on_near_claimed(
+            account_id.clone(),
+            amount.into(),
+            claim_id.into(),
+            msg,
+            receiver_id.as_ref(),
+            amount,
+            env::prepaid_gas() - GAS_FOR_CLAIM_NEAR_CALL,
+        )
+        .then(ext_claim_call_self::resolve_claim_call(
+            account_id,
+            receiver_id.into(),
+            amount.into(),
+            claim_id.into(),
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_CLAIM_CALL,
+        ))
+        .into()
+    }
+
+    /// Holds the unused NEAR returned by the receiver of the claim until the resolve.
+    /// Returns the attached amount, so the receiver can return this promise from
+    /// `on_near_claimed`.
+    #[payable]
+    pub fn return_claimed_near(&mut self, claim_id: U64) -> U128 {
+        let mut claim_call = self
+            .claim_calls
+            .get(&claim_id.0)
+            .expect("The claim is not pending");
+        if claim_call.receiver_id != env::predecessor_account_id() {
+            env::panic(b"Only the receiver of the claim can return NEAR");
+        }
+        let amount = env::attached_deposit();
+        claim_call.returned_amount += amount;
+        self.claim_calls.insert(&claim_id.0, &claim_call);
+        amount.into()
+    }
+
+    /// Puts the unused NEAR back to the claimer's NEAR balance. If the call has failed, the whole
+    /// amount is refunded to the farm and put back. The returned NEAR above the unused amount is
+    /// sent back to the receiver.
+    /// Returns the amount of NEAR used by the receiver.
+    #[private]
+    pub fn resolve_claim_call(
+        &mut self,
+        account_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        claim_id: U64,
+    ) -> U128 {
+        let amount: Balance = amount.into();
+        let returned_amount = self
+            .claim_calls
+            .remove(&claim_id.0)
+            .map(|claim_call| claim_call.returned_amount)
+            .unwrap_or(0);
+        let (unused_amount, excess_returned_amount) = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(value) => {
+                let unused_amount = near_sdk::serde_json::from_slice::<U128>(&value)
+                    .map(|unused_amount| unused_amount.0)
+                    .unwrap_or(0);
+                let unused_amount =
+                    std::cmp::min(std::cmp::min(amount, unused_amount), returned_amount);
+                (unused_amount, returned_amount - unused_amount)
+            }
+            PromiseResult::Failed => (amount, returned_amount),
+        };
+        if excess_returned_amount > 0 {
+            Promise::new(receiver_id.clone()).transfer(excess_returned_amount);
+        }
+        if unused_amount > 0 {
+            self.restore_claim(&account_id, unused_amount);
+        }
+        let used_amount = amount - unused_amount;
+        if used_amount > 0 {
+            emit_farm_event(
+                "near_claim",
+                json!({
+                    "account_id": account_id,
+                    "receiver_id": receiver_id,
+                    "amount": U128(used_amount),
+                }),
+            );
+        }
+        if unused_amount > 0 {
+            emit_farm_event(
+                "near_claim_refund",
+                json!({
+                    "account_id": account_id,
+                    "receiver_id": receiver_id,
+                    "amount": U128(unused_amount),
+                }),
+            );
+        }
+        used_amount.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::test_utils::accounts;
+
+    fn resolve(farm: &mut Farm, claim_id: u64, amount: Balance, unused_amount: Balance) -> Balance {
+        let result = serde_json::to_vec(&U128(unused_amount)).unwrap();
+        set_callback_context(PromiseResult::Successful(result));
+        farm.resolve_claim_call(
+            accounts(2).into(),
+            accounts(3).into(),
+            amount.into(),
+            claim_id.into(),
+        )
+        .0
+    }
+
+    #[test]
+    fn test_overlapping_claim_calls() {
+        set_context(accounts(0), 0, 0);
+        let mut farm = Farm::new(accounts(1), accounts(0), None, None, None, None);
+        set_context(accounts(0), 0, 1);
+        farm.set_reward_stream_duration(0.into());
+        set_context(accounts(2), 0, ONE_NEAR);
+        farm.storage_deposit(None, None);
+        set_context(accounts(1), 0, 0);
+        farm.ft_on_transfer(
+            accounts(2),
+            ONE_NEAR.into(),
+            "\"DepositAndStake\"".to_string(),
+        );
+
+        set_context(accounts(4), 0, 6 * ONE_NEAR);
+        farm.take_my_near();
+        set_context(accounts(2), 0, 1);
+        farm.claim_near_call(accounts(3), String::new());
+        set_context(accounts(4), 0, 4 * ONE_NEAR);
+        farm.take_my_near();
+        set_context(accounts(2), 0, 1);
+        farm.claim_near_call(accounts(3), String::new());
+
+        set_context(accounts(3), 0, ONE_NEAR);
+        farm.return_claimed_near(1.into());
+        set_context(accounts(3), 0, 2 * ONE_NEAR);
+        farm.return_claimed_near(0.into());
+
+        assert_eq!(
+            resolve(&mut farm, 0, 6 * ONE_NEAR, 2 * ONE_NEAR),
+            4 * ONE_NEAR
+        );
+        assert_eq!(resolve(&mut farm, 1, 4 * ONE_NEAR, ONE_NEAR), 3 * ONE_NEAR);
+        let account = farm.get_account(accounts(2)).unwrap();
+        assert_eq!(account.near_balance.0, 3 * ONE_NEAR);
+    }
+}
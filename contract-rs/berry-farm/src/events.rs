@@ -0,0 +1,80 @@
+//! NEP-297 events. Cucumber balance changes follow the NEP-141 event standard, everything else
+//! is logged under the `berryfarm` standard.
+use crate::*;
+use near_sdk::log;
+use near_sdk::serde_json::{json, Value};
+
+const NEP141_STANDARD: &str = "nep141";
+const NEP141_VERSION: &str = "1.0.0";
+
+const FARM_STANDARD: &str = "berryfarm";
+const FARM_VERSION: &str = "1.0.0";
+
+fn emit_event(standard: &str, version: &str, event: &str, data: Value) {
+    log!(
+        "EVENT_JSON:{}",
+        json!({
+            "standard": standard,
+            "version": version,
+            "event": event,
+            "data": [data],
+        })
+    );
+}
+
+fn with_memo(mut data: Value, memo: Option<&str>) -> Value {
+    if let Some(memo) = memo {
+        data["memo"] = memo.into();
+    }
+    data
+}
+
+pub(crate) fn emit_ft_mint(owner_id: &str, amount: Balance, memo: Option<&str>) {
+    let data = json!({
+        "owner_id": owner_id,
+        "amount": U128(amount),
+    });
+    emit_event(
+        NEP141_STANDARD,
+        NEP141_VERSION,
+        "ft_mint",
+        with_memo(data, memo),
+    );
+}
+
+pub(crate) fn emit_ft_burn(owner_id: &str, amount: Balance, memo: Option<&str>) {
+    let data = json!({
+        "owner_id": owner_id,
+        "amount": U128(amount),
+    });
+    emit_event(
+        NEP141_STANDARD,
+        NEP141_VERSION,
+        "ft_burn",
+        with_memo(data, memo),
+    );
+}
+
+pub(crate) fn emit_ft_transfer(
+    old_owner_id: &str,
+    new_owner_id: &str,
+    amount: Balance,
+    memo: Option<&str>,
+) {
+    let data = json!({
+        "old_owner_id": old_owner_id,
+        "new_owner_id": new_owner_id,
+        "amount": U128(amount),
+    });
+    emit_event(
+        NEP141_STANDARD,
+        NEP141_VERSION,
+        "ft_transfer",
+        with_memo(data, memo),
+    );
+}
+
+/// Emits a farm specific event, e.g. `near_claim` or `vault_create`.
+pub(crate) fn emit_farm_event(event: &str, data: Value) {
+    emit_event(FARM_STANDARD, FARM_VERSION, event, data);
+}
@@ -1,30 +1,21 @@
 use crate::*;
 use near_contract_standards::fungible_token::core::FungibleTokenCore;
 use near_contract_standards::fungible_token::core_impl::ext_fungible_token_receiver;
-use near_sdk::{assert_one_yocto, log, Gas, PromiseOrValue, PromiseResult};
+use near_sdk::{assert_one_yocto, Gas, PromiseOrValue, PromiseResult};
 
 const GAS_FOR_RESOLVE_TRANSFER: Gas = 5_000_000_000_000;
 const GAS_FOR_FT_TRANSFER_CALL: Gas = 25_000_000_000_000 + GAS_FOR_RESOLVE_TRANSFER;
 
-const NO_DEPOSIT: Balance = 0;
-
 #[near_bindgen]
 impl FungibleTokenCore for Farm {
     #[payable]
     fn ft_transfer(&mut self, receiver_id: ValidAccountId, amount: U128, memo: Option<String>) {
         assert_one_yocto();
+        self.assert_transfers_not_paused();
         let amount = amount.into();
-        let sender_id = self.withdraw_from_sender(receiver_id.as_ref(), amount);
-        self.deposit_to_account(receiver_id.as_ref(), amount);
-        log!(
-            "Transfer 🥒{} from {} to {}",
-            amount,
-            sender_id,
-            receiver_id
-        );
-        if let Some(memo) = memo {
-            log!("Memo: {}", memo);
-        }
+        let (sender_id, seed_balances) = self.withdraw_from_sender(receiver_id.as_ref(), amount);
+        self.deposit_to_account(receiver_id.as_ref(), &seed_balances);
+        emit_ft_transfer(&sender_id, receiver_id.as_ref(), amount, memo.as_deref());
     }
 
     #[payable]
@@ -36,18 +27,11 @@ impl FungibleTokenCore for Farm {
         msg: String,
     ) -> PromiseOrValue<U128> {
         assert_one_yocto();
+        self.assert_transfers_not_paused();
         let amount = amount.into();
-        let sender_id = self.withdraw_from_sender(receiver_id.as_ref(), amount);
-        self.deposit_to_account(receiver_id.as_ref(), amount);
-        log!(
-            "Transfer 🥒{} from {} to {}",
-            amount,
-            sender_id,
-            receiver_id
-        );
-        if let Some(memo) = memo {
-            log!("Memo: {}", memo);
-        }
+        let (sender_id, seed_balances) = self.withdraw_from_sender(receiver_id.as_ref(), amount);
+        self.deposit_to_account(receiver_id.as_ref(), &seed_balances);
+        emit_ft_transfer(&sender_id, receiver_id.as_ref(), amount, memo.as_deref());
         // Initiating receiver's call and the callback
         ext_fungible_token_receiver::ft_on_transfer(
             sender_id.clone(),
@@ -125,25 +109,30 @@ impl FungibleTokenResolver for Farm {
         };
 
         if unused_amount > 0 {
-            let (receiver_account_id_hash, mut receiver_account) =
-                self.get_mut_account(&receiver_id);
+            let (receiver_account_id_hash, receiver_account) =
+                self.get_internal_account(&receiver_id);
 
-            let receiver_balance = receiver_account.cucumber_balance;
-            if receiver_balance > 0 {
-                let refund_amount = std::cmp::min(receiver_balance, unused_amount);
-                receiver_account.cucumber_balance -= refund_amount;
-                self.save_account(&receiver_account_id_hash, &receiver_account);
+            if let Some(mut receiver_account) = receiver_account {
+                self.touch(&mut receiver_account);
+                let receiver_balance = receiver_account.unlocked_cucumber_balance();
+                if receiver_balance > 0 {
+                    let refund_amount = std::cmp::min(receiver_balance, unused_amount);
+                    let seed_balances = receiver_account.withdraw_cucumbers(refund_amount);
+                    self.save_account(&receiver_account_id_hash, &mut receiver_account);
 
-                let (sender_account_id_hash, mut sender_account) = self.get_mut_account(&sender_id);
-                sender_account.cucumber_balance += refund_amount;
-                self.save_account(&sender_account_id_hash, &sender_account);
-                log!(
-                    "Refund 🥒{} from {} to {}",
-                    refund_amount,
-                    receiver_id,
-                    sender_id
-                );
-                return (amount - refund_amount).into();
+                    let (sender_account_id_hash, sender_account) =
+                        self.get_internal_account(&sender_id);
+                    if let Some(mut sender_account) = sender_account {
+                        self.touch(&mut sender_account);
+                        sender_account.deposit_cucumbers(&seed_balances);
+                        self.save_account(&sender_account_id_hash, &mut sender_account);
+                        emit_ft_transfer(&receiver_id, &sender_id, refund_amount, Some("refund"));
+                    } else {
+                        // The sender has unregistered in the meantime, so the refund is burned.
+                        self.burn_cucumbers(&receiver_id, &seed_balances, Some("refund"));
+                    }
+                    return (amount - refund_amount).into();
+                }
             }
         }
         amount.into()
@@ -2,12 +2,9 @@ use crate::*;
 use near_contract_standards::storage_management::{
     StorageBalance, StorageBalanceBounds, StorageManagement,
 };
+use near_sdk::assert_one_yocto;
 use std::convert::TryInto;
-use std::mem::size_of;
 
-const STORAGE_AMOUNT_BYTES: usize = size_of::<Account>() + 21 + 40;
-
-#[allow(unused_variables)]
 #[near_bindgen]
 impl StorageManagement for Farm {
     #[payable]
@@ -19,15 +16,30 @@ impl StorageManagement for Farm {
         let account_id = account_id
             .map(|a| a.into())
             .unwrap_or_else(env::predecessor_account_id);
+        let registration_only = registration_only.unwrap_or(false);
         let attached_deposit = env::attached_deposit();
-        let (account_id_hash, account) = self.get_mut_account(&account_id);
-        let refund_amount = if self.accounts.contains_key(&account_id_hash) {
-            attached_deposit
+        let (account_id_hash, account) = self.get_internal_account(&account_id);
+        let refund_amount = if let Some(mut account) = account {
+            if registration_only {
+                attached_deposit
+            } else {
+                account.storage_balance += attached_deposit;
+                self.save_account(&account_id_hash, &mut account);
+                0
+            }
         } else {
-            self.save_account(&account_id_hash, &account);
-            let amount = self.storage_balance_bounds().min.0;
-            assert!(attached_deposit >= amount);
-            attached_deposit - amount
+            let min_balance = self.storage_balance_bounds().min.0;
+            if attached_deposit < min_balance {
+                env::panic(b"The attached deposit is less than the minimum storage balance");
+            }
+            let mut account = self.new_account();
+            account.storage_balance = if registration_only {
+                min_balance
+            } else {
+                attached_deposit
+            };
+            self.save_account(&account_id_hash, &mut account);
+            attached_deposit - account.storage_balance
         };
         if refund_amount > 0 {
             Promise::new(env::predecessor_account_id()).transfer(refund_amount);
@@ -36,30 +48,145 @@ impl StorageManagement for Farm {
             .unwrap()
     }
 
+    #[payable]
     fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
-        env::panic(b"Unimplemented");
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let (account_id_hash, account) = self.get_internal_account(&account_id);
+        let mut account = account.expect("The account is not registered");
+        let available_balance = account.available_storage_balance();
+        let amount = amount.map(|a| a.0).unwrap_or(available_balance);
+        if amount > available_balance {
+            env::panic(b"The amount is greater than the available storage balance");
+        }
+        if amount > 0 {
+            account.storage_balance -= amount;
+            self.save_account(&account_id_hash, &mut account);
+            Promise::new(account_id.clone()).transfer(amount);
+        }
+        self.storage_balance_of(account_id.try_into().unwrap())
+            .unwrap()
     }
 
+    /// Removes the predecessor's account and refunds the paid storage deposit together with the
+    /// unclaimed NEAR. The unclaimed NEAR goes to the reward split or the reward beneficiary
+    /// instead if the account has one. The storage credit granted by the farm is not refunded.
+    /// Accounts with unclaimed NEAR can't unregister while the claims are paused.
+    /// Without `force` the account should have no cucumbers, no unbonding bananas, no unclaimed
+    /// bananas and no unclaimed reward tokens. With `force` they are burned.
+    #[payable]
     fn storage_unregister(&mut self, force: Option<bool>) -> bool {
-        env::panic(b"Unimplemented");
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        if self.get_internal_account(&account_id).1.is_none() {
+            return false;
+        }
+        let (account_id_hash, account) = self.get_mut_account(&account_id);
+        let mut near_amount = account.near_balance;
+        if near_amount > 0 {
+            // Unregistering pays out the unclaimed NEAR.
+            self.assert_claims_not_paused();
+            if account.reward_beneficiary.is_some() || !account.reward_split.is_empty() {
+                self.internal_claim_near(&account_id, None);
+                near_amount = 0;
+            }
+        }
+        if account.cucumber_balance > 0
+            || account.has_unbonding_balance()
+            || account.banana_balance > 0
+            || account.wrapped_near_balance > 0
+            || self.has_token_rewards(&account)
+        {
+            if !force.unwrap_or(false) {
+                env::panic(b"Can't unregister the account with the positive balance without force");
+            }
+            self.burn_cucumbers(
+                &account_id,
+                &account.seed_cucumber_balances,
+                Some("unregister"),
+            );
+            self.total_lock_boost -= account.lock_boost;
+            // The owed wNEAR stays on the farm's wNEAR account.
+            self.total_wrapped_near_owed -= account.wrapped_near_balance;
+        }
+        self.remove_account(&account_id_hash);
+
+        self.total_near_claimed += near_amount;
+        emit_farm_event(
+            "account_unregister",
+            json!({
+                "account_id": account_id,
+                "near_amount": U128(near_amount),
+                "unbonding_amounts": account
+                    .unbonding_balances
+                    .iter()
+                    .map(|&balance| U128(balance))
+                    .collect::<Vec<_>>(),
+                "banana_amount": U128(account.banana_balance),
+                "storage_amount": U128(account.paid_storage_balance()),
+            }),
+        );
+        let refund_amount = near_amount + account.paid_storage_balance();
+        Promise::new(account_id).transfer(refund_amount);
+        true
     }
 
     fn storage_balance_bounds(&self) -> StorageBalanceBounds {
-        let v = STORAGE_AMOUNT_BYTES as Balance * env::storage_byte_cost();
         StorageBalanceBounds {
-            min: v.into(),
-            max: Some(v.into()),
+            min: (self.account_storage_usage as Balance * env::storage_byte_cost()).into(),
+            max: None,
         }
     }
 
     fn storage_balance_of(&self, account_id: ValidAccountId) -> Option<StorageBalance> {
-        if self.accounts.contains_key(&account_id.as_ref().into()) {
-            Some(StorageBalance {
-                total: self.storage_balance_bounds().min,
-                available: 0.into(),
+        self.get_internal_account(account_id.as_ref())
+            .1
+            .map(|account| StorageBalance {
+                total: account.storage_balance.into(),
+                available: account.available_storage_balance().into(),
             })
-        } else {
-            None
-        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_sdk::test_utils::accounts;
+    use near_sdk::PromiseResult;
+
+    #[test]
+    fn test_unregister_pays_reward_beneficiary() {
+        set_context(accounts(0), 0, 0);
+        let mut farm = Farm::new(accounts(1), accounts(0), None, None, None, None);
+        set_context(accounts(0), 0, 1);
+        farm.set_reward_stream_duration(0.into());
+        set_context(accounts(2), 0, ONE_NEAR);
+        farm.storage_deposit(None, None);
+        set_context(accounts(1), 0, 0);
+        farm.ft_on_transfer(
+            accounts(2),
+            ONE_NEAR.into(),
+            "\"DepositAndStake\"".to_string(),
+        );
+        set_context(accounts(2), 0, 1);
+        farm.set_reward_beneficiary(Some(accounts(3)));
+        set_context(accounts(4), 0, 6 * ONE_NEAR);
+        farm.take_my_near();
+
+        set_context(accounts(2), 0, 1);
+        assert!(farm.storage_unregister(Some(true)));
+        assert_eq!(farm.total_near_claimed, 6 * ONE_NEAR);
+
+        // The failed payout to the beneficiary of the unregistered account isn't refunded to it.
+        set_callback_context(PromiseResult::Failed);
+        let payouts = vec![ClaimPayout {
+            receiver_id: accounts(3).into(),
+            amount: (6 * ONE_NEAR).into(),
+        }];
+        assert_eq!(farm.resolve_claim(accounts(2).into(), payouts).0, 0);
+        assert_eq!(farm.total_near_claimed, 0);
+        assert_eq!(farm.get_undistributed_near().0, 6 * ONE_NEAR);
     }
 }
@@ -11,9 +11,6 @@ impl FungibleTokenReceiver for Farm {
         amount: U128,
         msg: String,
     ) -> PromiseOrValue<U128> {
-        if &env::predecessor_account_id() != &self.banana_token_account_id {
-            env::panic(b"This farm can only receive bananas through a contract API");
-        }
         let payload: OnReceiverPayload =
             serde_json::from_str(&msg).expect("Failed to parse the payload");
 
@@ -21,13 +18,28 @@ impl FungibleTokenReceiver for Farm {
 
         match payload {
             OnReceiverPayload::DepositAndStake => {
-                let (account_id_hash, mut account) = self.get_mut_account(sender_id.as_ref());
-                account.cucumber_balance += amount;
-                self.save_account(&account_id_hash, &account);
-
-                self.total_cucumber_balance += amount;
+                let seed_index = self.predecessor_seed_index();
+                self.assert_staking_not_paused();
+                self.stake_seed(seed_index, sender_id.as_ref(), amount);
                 PromiseOrValue::Value(0.into())
             }
+            OnReceiverPayload::DepositAndLock { duration } => {
+                let seed_index = self.predecessor_seed_index();
+                self.assert_staking_not_paused();
+                let cucumber_amount = self.stake_seed(seed_index, sender_id.as_ref(), amount);
+                self.lock_cucumbers(
+                    sender_id.as_ref(),
+                    seed_index,
+                    cucumber_amount,
+                    duration.into(),
+                );
+                PromiseOrValue::Value(0.into())
+            }
+            OnReceiverPayload::DepositReward => {
+                let unused_amount =
+                    self.deposit_reward(&env::predecessor_account_id(), sender_id.as_ref(), amount);
+                PromiseOrValue::Value(unused_amount.into())
+            }
         }
     }
 }
@@ -51,6 +51,10 @@ pub struct Vault {
     pub receiver_id_hash: ShortAccountHash,
     /// The remaining amount of tokens in the safe.
     pub balance: Balance,
+    /// The storage cost of the vault paid by the sender. It's refunded when the vault is resolved.
+    pub storage_balance: Balance,
+    /// The remaining tokens by the seed index they can be redeemed for.
+    pub seed_balances: Vec<Balance>,
 }
 
 #[ext_contract(ext_token_receiver)]
@@ -90,7 +94,8 @@ trait VaultFungibleToken {
     /// - Withdraws `amount` from the `predecessor_id` account.
     /// - Creates a new local safe with a new unique `safe_id` with the following content:
     ///     `{sender_id: predecessor_id, amount: amount, receiver_id: receiver_id}`
-    /// - Saves this safe to the storage.
+    /// - Saves this safe to the storage. The storage is paid from the attached deposit and the
+    ///   available storage balance of the `predecessor_id`.
     /// - Calls on `receiver_id` method `on_token_receive(sender_id: predecessor_id, amount, safe_id, payload)`/
     /// - Attaches a self callback to this promise `resolve_safe(safe_id, sender_id)`
     fn transfer_with_vault(
@@ -111,9 +116,11 @@ impl VaultFungibleToken for Farm {
     #[payable]
     fn transfer_raw(&mut self, receiver_id: ValidAccountId, amount: U128) {
         assert_paid();
+        self.assert_transfers_not_paused();
         let amount = amount.into();
-        self.withdraw_from_sender(receiver_id.as_ref(), amount);
-        self.deposit_to_account(receiver_id.as_ref(), amount);
+        let (sender_id, seed_balances) = self.withdraw_from_sender(receiver_id.as_ref(), amount);
+        self.deposit_to_account(receiver_id.as_ref(), &seed_balances);
+        emit_ft_transfer(&sender_id, receiver_id.as_ref(), amount, None);
     }
 
     #[payable]
@@ -124,6 +131,7 @@ impl VaultFungibleToken for Farm {
         payload: String,
     ) -> Promise {
         assert_paid();
+        self.assert_transfers_not_paused();
         let gas_to_receiver =
             env::prepaid_gas().saturating_sub(GAS_FOR_REMAINING_COMPUTE + GAS_FOR_CALLBACK);
 
@@ -132,16 +140,32 @@ impl VaultFungibleToken for Farm {
         }
 
         let amount = amount.into();
-        let sender_id = self.withdraw_from_sender(receiver_id.as_ref(), amount);
+        let (sender_id, seed_balances) = self.withdraw_from_sender(receiver_id.as_ref(), amount);
 
         // Creating a new vault
         let vault_id = self.next_vault_id;
         self.next_vault_id = vault_id.next();
-        let vault = Vault {
+        let initial_storage_usage = env::storage_usage();
+        let mut vault = Vault {
             receiver_id_hash: receiver_id.as_ref().into(),
             balance: amount,
+            storage_balance: 0,
+            seed_balances,
         };
         self.vaults.insert(&vault_id, &vault);
+        vault.storage_balance =
+            (env::storage_usage() - initial_storage_usage) as Balance * env::storage_byte_cost();
+        self.vaults.insert(&vault_id, &vault);
+        self.charge_vault_storage(&sender_id, vault.storage_balance);
+        emit_farm_event(
+            "vault_create",
+            json!({
+                "vault_id": vault_id,
+                "sender_id": sender_id,
+                "receiver_id": receiver_id,
+                "amount": U128(amount),
+            }),
+        );
 
         // Calling the receiver
         ext_token_receiver::on_receive_with_vault(
@@ -168,9 +192,9 @@ impl VaultFungibleToken for Farm {
         receiver_id: ValidAccountId,
         amount: U128,
     ) {
-        let mut vault = self.vaults.get(&vault_id).expect("Vault doesn't exist");
+        let mut vault = self.get_vault(&vault_id).expect("Vault doesn't exist");
         let vault_receiver_id = env::predecessor_account_id();
-        if &ShortAccountHash::from(&vault_receiver_id) != &vault.receiver_id_hash {
+        if ShortAccountHash::from(&vault_receiver_id) != vault.receiver_id_hash {
             env::panic(b"The vault is not owned by the predecessor");
         }
         let amount = amount.into();
@@ -178,9 +202,18 @@ impl VaultFungibleToken for Farm {
             env::panic(b"Not enough balance in the vault");
         }
         vault.balance -= amount;
+        let seed_balances = take_seed_balances(&mut vault.seed_balances, amount);
         self.vaults.insert(&vault_id, &vault);
 
-        self.deposit_to_account(receiver_id.as_ref(), amount);
+        self.deposit_to_account(receiver_id.as_ref(), &seed_balances);
+        emit_farm_event(
+            "vault_withdraw",
+            json!({
+                "vault_id": vault_id,
+                "receiver_id": receiver_id,
+                "amount": U128(amount),
+            }),
+        );
     }
 
     fn resolve_vault(&mut self, vault_id: VaultId, sender_id: AccountId) -> U128 {
@@ -188,11 +221,29 @@ impl VaultFungibleToken for Farm {
             env::panic(b"This method is private");
         }
 
-        let vault = self.vaults.remove(&vault_id).expect("Vault doesn't exist");
-
-        if vault.balance > 0 {
-            self.deposit_to_account(&sender_id, vault.balance);
+        let vault = self.remove_vault(&vault_id).expect("Vault doesn't exist");
+
+        let (account_id_hash, account) = self.get_internal_account(&sender_id);
+        if let Some(mut account) = account {
+            self.touch(&mut account);
+            account.deposit_cucumbers(&vault.seed_balances);
+            account.storage_balance += vault.storage_balance;
+            self.save_account(&account_id_hash, &mut account);
+        } else {
+            // The sender has unregistered in the meantime, so the remaining balance is burned.
+            self.burn_cucumbers(&sender_id, &vault.seed_balances, Some("refund"));
+            if vault.storage_balance > 0 {
+                Promise::new(sender_id.clone()).transfer(vault.storage_balance);
+            }
         }
+        emit_farm_event(
+            "vault_resolve",
+            json!({
+                "vault_id": vault_id,
+                "sender_id": sender_id,
+                "amount": U128(vault.balance),
+            }),
+        );
 
         vault.balance.into()
     }
@@ -212,12 +263,12 @@ impl VaultFungibleToken for Farm {
 
 impl Farm {
     /// Withdraws `amount` from the `predecessor_id` while comparing it to the `receiver_id`.
-    /// Return `predecessor_id` and hash of the predecessor
+    /// Returns `predecessor_id` and the withdrawn cucumbers by the seed index.
     pub(crate) fn withdraw_from_sender(
         &mut self,
         receiver_id: &AccountId,
         amount: Balance,
-    ) -> AccountId {
+    ) -> (AccountId, Vec<Balance>) {
         if amount == 0 {
             env::panic(b"Transfer amount should be positive");
         }
@@ -230,30 +281,56 @@ impl Farm {
         let (account_id_hash, mut account) = self.get_mut_account(&sender_id.clone());
 
         // Checking and updating the balance
-        if account.cucumber_balance < amount {
-            env::panic(b"Not enough cucumber balance");
+        if account.unlocked_cucumber_balance() < amount {
+            env::panic(b"Not enough unlocked cucumber balance");
         }
-        account.cucumber_balance -= amount;
+        let seed_balances = account.withdraw_cucumbers(amount);
 
         // Saving the account back to the state.
-        self.save_account(&account_id_hash, &account);
+        self.save_account(&account_id_hash, &mut account);
 
-        sender_id
+        (sender_id, seed_balances)
     }
 
-    /// Deposits `amount` to the `account_id`
-    pub(crate) fn deposit_to_account(&mut self, account_id: &AccountId, amount: Balance) {
-        if amount == 0 {
+    fn get_vault(&self, vault_id: &VaultId) -> Option<Vault> {
+        self.vaults
+            .get(vault_id)
+            .or_else(|| self.vaults_v0.get(vault_id).map(|vault| vault.into()))
+    }
+
+    /// Removes the vault of any layout.
+    pub(crate) fn remove_vault(&mut self, vault_id: &VaultId) -> Option<Vault> {
+        let vault = self.vaults.remove(vault_id);
+        let vault_v0 = self.vaults_v0.remove(vault_id);
+        vault.or_else(|| vault_v0.map(|vault| vault.into()))
+    }
+
+    /// Charges the storage cost of a new vault. The attached deposit goes to the storage balance
+    /// of the sender first, and then the cost is taken from the storage balance.
+    fn charge_vault_storage(&mut self, sender_id: &AccountId, storage_cost: Balance) {
+        let (account_id_hash, account) = self.get_internal_account(sender_id);
+        let mut account = account.expect("The account is not registered");
+        account.storage_balance += env::attached_deposit();
+        if account.available_storage_balance() < storage_cost {
+            env::panic(b"Not enough storage balance to create a vault. Attach more deposit");
+        }
+        account.storage_balance -= storage_cost;
+        self.save_account(&account_id_hash, &mut account);
+    }
+
+    /// Deposits the cucumbers by the seed index to the `account_id`
+    pub(crate) fn deposit_to_account(&mut self, account_id: &AccountId, seed_balances: &[Balance]) {
+        if seed_balances.iter().all(|&balance| balance == 0) {
             return;
         }
         // Retrieving the account from the state.
-        let (account_id_hash, account) = self.get_internal_account(&account_id);
+        let (account_id_hash, account) = self.get_internal_account(account_id);
         let mut account = account.expect("Receiver account doesn't exist");
         self.touch(&mut account);
 
-        account.cucumber_balance += amount;
+        account.deposit_cucumbers(seed_balances);
         // Saving the account back to the state.
-        self.save_account(&account_id_hash, &account);
+        self.save_account(&account_id_hash, &mut account);
     }
 }
 
@@ -1,27 +1,66 @@
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_contract_standards::storage_management::StorageManagement;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::collections::LookupMap;
-use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::json_types::{ValidAccountId, WrappedDuration, WrappedTimestamp, U128};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::serde_json;
-use near_sdk::{env, ext_contract, near_bindgen, AccountId, Balance, PanicOnDefault, Promise};
-
+use near_sdk::serde_json::json;
+use near_sdk::{
+    env, ext_contract, near_bindgen, AccountId, Balance, Duration, Gas, PanicOnDefault, Promise,
+    StorageUsage, Timestamp,
+};
+
+mod claim;
+mod claim_call;
+mod events;
 mod ft_core;
 mod ft_meta;
 mod ft_storage;
 mod fungible_token_receiver;
 mod legacy_token;
-
-pub use crate::fungible_token_receiver::*;
+mod lock;
+mod migration;
+mod owner;
+mod pause;
+mod reward_stream;
+mod reward_tokens;
+mod seeds;
+mod unstake;
+mod upgrade;
+mod wrap_near;
+
+pub use crate::claim::*;
+pub use crate::claim_call::*;
+use crate::events::*;
+pub use crate::migration::*;
+use crate::owner::*;
+pub use crate::pause::*;
+pub use crate::reward_stream::*;
+pub use crate::reward_tokens::*;
+pub use crate::seeds::*;
+pub use crate::upgrade::*;
+pub use crate::wrap_near::*;
 
 pub use crate::legacy_token::*;
+pub use crate::lock::*;
 
 #[global_allocator]
 static ALLOC: near_sdk::wee_alloc::WeeAlloc<'_> = near_sdk::wee_alloc::WeeAlloc::INIT;
 
-uint::construct_uint! {
-    /// 256-bit unsigned integer.
-    pub struct U256(4);
+// The lints of the macro expansion.
+#[allow(
+    clippy::manual_range_contains,
+    clippy::assign_op_pattern,
+    clippy::ptr_offset_with_cast
+)]
+mod u256 {
+    uint::construct_uint! {
+        /// 256-bit unsigned integer.
+        pub struct U256(4);
+    }
 }
+pub use u256::U256;
 
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct Account {
@@ -29,6 +68,122 @@ pub struct Account {
     pub near_balance: Balance,
     pub cucumber_balance: Balance,
     pub near_claimed: Balance,
+    /// The remainder of the earned NEAR scaled by `NEAR_PER_CUCUMBER_DENOM`, carried to the
+    /// next `touch`.
+    pub near_balance_remainder: Balance,
+    /// Unstaked seed tokens that are waiting to be withdrawn by the seed index. They don't earn
+    /// NEAR. Always has `MAX_SEEDS` slots.
+    pub unbonding_balances: Vec<Balance>,
+    /// The timestamp after which the unbonding balances can be withdrawn.
+    pub unbonding_release_timestamp: Timestamp,
+    pub last_banana_per_cucumber_numer: Balance,
+    /// Bananas earned from instant unstake penalties of other accounts.
+    pub banana_balance: Balance,
+    pub banana_claimed: Balance,
+    /// The storage deposit paid by the account.
+    pub storage_balance: Balance,
+    /// The measured storage occupied by the account record.
+    pub storage_usage: StorageUsage,
+    /// The account that receives the NEAR claims instead of this account.
+    pub reward_beneficiary: Option<AccountId>,
+    /// The shares of the NEAR claims. Takes precedence over the reward beneficiary.
+    pub reward_split: Vec<RewardSplit>,
+    /// The minimum NEAR balance claimed by `claim_for_accounts`. `None` if not opted in.
+    pub auto_claim_threshold: Option<Balance>,
+    /// The slots of the reward pools by the pool index. Always has `MAX_REWARD_TOKENS` slots.
+    pub token_rewards: Vec<TokenReward>,
+    /// The cucumbers by the seed index that can't be transferred or unstaked until the lock ends.
+    /// Always has `MAX_SEEDS` slots.
+    pub locked_seed_balances: Vec<Balance>,
+    pub lock_end_timestamp: Timestamp,
+    /// The extra reward weight of the locked cucumbers.
+    pub lock_boost: Balance,
+    /// The part of the storage balance granted by the farm to the accounts that were registered
+    /// for free before the storage deposits. It covers the storage, but is never refunded.
+    pub storage_credit: Balance,
+    /// The cucumbers by the index of the seed they can be redeemed for. Adds up to the cucumber
+    /// balance. Always has `MAX_SEEDS` slots.
+    pub seed_cucumber_balances: Vec<Balance>,
+    /// The claimed NEAR that is held as wNEAR by the farm, because a wrapped claim has failed and
+    /// couldn't be unwrapped back. Sent with the next `claim_near_wrapped`.
+    pub wrapped_near_balance: Balance,
+}
+
+impl Account {
+    /// Returns the part of the storage deposit that is not covering the account storage.
+    /// The storage credit is never available.
+    pub fn available_storage_balance(&self) -> Balance {
+        let storage_cost = self.storage_usage as Balance * env::storage_byte_cost();
+        self.storage_balance - std::cmp::max(storage_cost, self.storage_credit)
+    }
+
+    /// Returns the storage deposit actually paid by the account.
+    pub fn paid_storage_balance(&self) -> Balance {
+        self.storage_balance - self.storage_credit
+    }
+
+    /// Returns the unbonding balance of the seed that can be withdrawn at the current block.
+    pub fn unbonded_balance(&self, seed_index: usize) -> Balance {
+        if env::block_timestamp() >= self.unbonding_release_timestamp {
+            self.unbonding_balances[seed_index]
+        } else {
+            0
+        }
+    }
+
+    pub fn has_unbonding_balance(&self) -> bool {
+        self.unbonding_balances.iter().any(|&balance| balance > 0)
+    }
+
+    /// Returns the cucumbers that are still locked at the current block.
+    pub fn locked_cucumber_balance(&self) -> Balance {
+        if env::block_timestamp() < self.lock_end_timestamp {
+            self.locked_seed_balances.iter().sum()
+        } else {
+            0
+        }
+    }
+
+    /// Returns the cucumbers that can be transferred or unstaked.
+    pub fn unlocked_cucumber_balance(&self) -> Balance {
+        self.cucumber_balance - self.locked_cucumber_balance()
+    }
+
+    /// Returns the cucumbers of the seed that can be transferred or unstaked.
+    pub fn unlocked_seed_balance(&self, seed_index: usize) -> Balance {
+        if env::block_timestamp() < self.lock_end_timestamp {
+            self.seed_cucumber_balances[seed_index] - self.locked_seed_balances[seed_index]
+        } else {
+            self.seed_cucumber_balances[seed_index]
+        }
+    }
+
+    /// Returns the reward weight of the account, the cucumbers with the lock boost.
+    pub fn weighted_balance(&self) -> Balance {
+        self.cucumber_balance + self.lock_boost
+    }
+
+    /// Takes `amount` of the unlocked cucumbers from the account, in the order of the seeds.
+    /// Returns the taken cucumbers by the seed index.
+    pub fn withdraw_cucumbers(&mut self, amount: Balance) -> Vec<Balance> {
+        let mut unlocked_balances: Vec<Balance> = (0..MAX_SEEDS)
+            .map(|seed_index| self.unlocked_seed_balance(seed_index))
+            .collect();
+        let seed_balances = take_seed_balances(&mut unlocked_balances, amount);
+        for (balance, taken_amount) in self.seed_cucumber_balances.iter_mut().zip(&seed_balances) {
+            *balance -= taken_amount;
+        }
+        self.cucumber_balance -= amount;
+        seed_balances
+    }
+
+    /// Adds the cucumbers by the seed index to the account.
+    pub fn deposit_cucumbers(&mut self, seed_balances: &[Balance]) {
+        for (balance, amount) in self.seed_cucumber_balances.iter_mut().zip(seed_balances) {
+            *balance += amount;
+            self.cucumber_balance += amount;
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -37,23 +192,58 @@ pub struct HumanAccount {
     pub near_balance: U128,
     pub cucumber_balance: U128,
     pub near_claimed: U128,
+    /// The unstaked seed tokens by the seed.
+    pub unbonding: Vec<HumanUnbonding>,
+    pub unbonding_release_timestamp: WrappedTimestamp,
+    pub banana_balance: U128,
+    pub banana_claimed: U128,
+    pub reward_beneficiary: Option<AccountId>,
+    pub reward_split: Vec<RewardSplit>,
+    pub auto_claim_threshold: Option<U128>,
+    pub token_rewards: Vec<HumanTokenReward>,
+    pub locked_balance: U128,
+    pub lock_end_timestamp: WrappedTimestamp,
+    /// The reward weight, the cucumbers with the lock boost.
+    pub weighted_balance: U128,
+    /// The wNEAR of the failed wrapped claims, sent with the next `claim_near_wrapped`.
+    pub wrapped_near_balance: U128,
 }
 
 #[derive(Serialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct HumanStats {
     pub total_cucumber_balance: U128,
+    /// The total reward weight, the cucumbers with the lock boosts.
+    pub total_weighted_balance: U128,
     pub total_near_claimed: U128,
     pub total_near_received: U128,
+    pub total_banana_claimed: U128,
+    pub total_banana_received: U128,
 }
 
 pub const NEAR_PER_CUCUMBER_DENOM: Balance = 1_000_000_000_000_000_000;
 
+/// 48 hours in nanoseconds.
+pub const DEFAULT_UNBONDING_PERIOD: Duration = 48 * 60 * 60 * 1_000_000_000;
+
+pub const MAX_BASIS_POINTS: u16 = 10_000;
+
+pub const ONE_YOCTO: Balance = 1;
+
+/// Gas for `ft_transfer` of the seed and reward tokens.
+pub const GAS_FOR_FT_TRANSFER: Gas = 10_000_000_000_000;
+
+/// 10% of the instantly unstaked bananas.
+pub const DEFAULT_INSTANT_UNSTAKE_PENALTY_BPS: u16 = 1_000;
+
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Farm {
     pub accounts: LookupMap<ShortAccountHash, Account>,
 
+    /// Accounts of the old layout that haven't been upgraded yet.
+    pub accounts_v0: LookupMap<ShortAccountHash, AccountV0>,
+
     pub banana_token_account_id: AccountId,
 
     pub near_per_cucumber_numer: Balance,
@@ -66,7 +256,71 @@ pub struct Farm {
 
     pub vaults: LookupMap<VaultId, Vault>,
 
+    /// Vaults of the old layout that haven't been resolved yet.
+    pub vaults_v0: LookupMap<VaultId, VaultV0>,
+
     pub next_vault_id: VaultId,
+
+    /// How long unstaked cucumbers stay unbonding before the bananas can be withdrawn.
+    pub unbonding_period: Duration,
+
+    /// Penalty in basis points for skipping the unbonding period with `instant_unstake`.
+    pub instant_unstake_penalty_bps: u16,
+
+    /// Accumulated instant unstake penalties per cucumber, similar to `near_per_cucumber_numer`.
+    pub banana_per_cucumber_numer: Balance,
+
+    pub total_banana_received: Balance,
+
+    pub total_banana_claimed: Balance,
+
+    /// The measured storage of a new account record. Defines the minimum storage deposit.
+    pub account_storage_usage: StorageUsage,
+
+    /// The account that can configure the farm.
+    pub owner_id: AccountId,
+
+    pub pause_status: PauseStatus,
+
+    /// How long a staged upgrade has to wait before it can be deployed.
+    pub upgrade_delay: Duration,
+
+    pub staged_upgrade: Option<StagedUpgrade>,
+
+    /// How long a new reward deposit is streamed to the cucumber holders.
+    pub reward_stream_duration: Duration,
+    /// The NEAR of the stream that is not released into `near_per_cucumber_numer` yet.
+    pub reward_stream_balance: Balance,
+    pub reward_stream_end_timestamp: Timestamp,
+    /// When the stream was released into `near_per_cucumber_numer` the last time.
+    pub reward_stream_last_timestamp: Timestamp,
+    /// The NEAR received while there were not enough cucumbers to distribute it.
+    pub undistributed_near: Balance,
+    /// The released NEAR that was not covered by `near_per_cucumber_numer` because of rounding.
+    /// It's added to the next distribution.
+    pub near_distribution_dust: Balance,
+    /// The NEAR covered by `near_per_cucumber_numer`, i.e. claimed or owed to the accounts.
+    pub total_near_distributed: Balance,
+    /// The wNEAR contract for `claim_near_wrapped`.
+    pub wrap_near_account_id: Option<AccountId>,
+    /// The wNEAR held by the farm for the accounts after the failed wrapped claims.
+    pub total_wrapped_near_owed: Balance,
+    /// The claims of `claim_near_call` that are not resolved yet, by the claim ID.
+    pub claim_calls: LookupMap<u64, ClaimCall>,
+    pub next_claim_call_id: u64,
+    /// The reward pools of the whitelisted tokens. Pools are never removed, so their indices are
+    /// stable.
+    pub reward_pools: Vec<RewardPool>,
+    /// The stakeable tokens. The bananas are always the first seed. Seeds are never removed, so
+    /// their indices are stable.
+    pub seeds: Vec<Seed>,
+    /// The metadata of the cucumber token. The default cucumber metadata is used if not set.
+    pub metadata: Option<FungibleTokenMetadata>,
+    /// The sum of the lock boosts of all accounts.
+    pub total_lock_boost: Balance,
+    pub max_lock_duration: Duration,
+    /// The extra reward weight of the cucumbers locked for `max_lock_duration`.
+    pub max_lock_boost_bps: u16,
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Clone, PartialEq)]
@@ -91,6 +345,12 @@ pub trait ExtVaultFungibleToken {
 #[serde(crate = "near_sdk::serde")]
 pub enum OnReceiverPayload {
     DepositAndStake,
+    /// Stakes the deposit and locks the minted cucumbers for the duration for a boosted weight.
+    DepositAndLock {
+        duration: WrappedDuration,
+    },
+    /// Deposits the whitelisted reward token to its reward pool.
+    DepositReward,
 }
 
 /// Implements a trait to receiver_id
@@ -107,7 +367,23 @@ pub trait VaultFungibleTokenReceiver {
 #[near_bindgen]
 impl Farm {
     #[init]
-    pub fn new(banana_token_account_id: ValidAccountId) -> Self {
+    pub fn new(
+        banana_token_account_id: ValidAccountId,
+        owner_id: ValidAccountId,
+        unbonding_period: Option<WrappedDuration>,
+        instant_unstake_penalty_bps: Option<u16>,
+        upgrade_delay: Option<WrappedDuration>,
+        metadata: Option<FungibleTokenMetadata>,
+    ) -> Self {
+        if let Some(metadata) = metadata.as_ref() {
+            metadata.assert_valid();
+            if metadata.decimals != CUCUMBER_DECIMALS {
+                env::panic(b"The cucumber metadata should have the cucumber decimals");
+            }
+        }
+        let instant_unstake_penalty_bps =
+            instant_unstake_penalty_bps.unwrap_or(DEFAULT_INSTANT_UNSTAKE_PENALTY_BPS);
+        assert_valid_penalty_bps(instant_unstake_penalty_bps);
         // Registering the account for banana token to be able to withdraw.
         ext_token::register_account(
             env::current_account_id(),
@@ -115,56 +391,93 @@ impl Farm {
             NO_DEPOSIT,
             GAS_FOR_ACCOUNT_REGISTRATION,
         );
-        Self {
-            accounts: LookupMap::new(b"a".to_vec()),
+        let banana_seed = Seed::new(
+            banana_token_account_id.clone().into(),
+            CUCUMBER_DECIMALS,
+            DEFAULT_MINT_RATIO_BPS,
+        );
+        let mut this = Self {
+            accounts: LookupMap::new(ACCOUNTS_PREFIX.to_vec()),
+            accounts_v0: LookupMap::new(ACCOUNTS_V0_PREFIX.to_vec()),
             banana_token_account_id: banana_token_account_id.into(),
             near_per_cucumber_numer: 0,
             total_cucumber_balance: 0,
             total_near_received: 0,
             total_near_claimed: 0,
-            vaults: LookupMap::new(b"v".to_vec()),
+            vaults: LookupMap::new(VAULTS_PREFIX.to_vec()),
+            vaults_v0: LookupMap::new(VAULTS_V0_PREFIX.to_vec()),
             next_vault_id: VaultId(0),
-        }
+            unbonding_period: unbonding_period
+                .map(|d| d.into())
+                .unwrap_or(DEFAULT_UNBONDING_PERIOD),
+            instant_unstake_penalty_bps,
+            banana_per_cucumber_numer: 0,
+            total_banana_received: 0,
+            total_banana_claimed: 0,
+            account_storage_usage: 0,
+            owner_id: owner_id.into(),
+            pause_status: PauseStatus::default(),
+            upgrade_delay: upgrade_delay
+                .map(|d| d.into())
+                .unwrap_or(DEFAULT_UPGRADE_DELAY),
+            staged_upgrade: None,
+            reward_stream_duration: DEFAULT_REWARD_STREAM_DURATION,
+            reward_stream_balance: 0,
+            reward_stream_end_timestamp: 0,
+            reward_stream_last_timestamp: env::block_timestamp(),
+            undistributed_near: 0,
+            near_distribution_dust: 0,
+            total_near_distributed: 0,
+            wrap_near_account_id: None,
+            total_wrapped_near_owed: 0,
+            claim_calls: LookupMap::new(CLAIM_CALLS_PREFIX.to_vec()),
+            next_claim_call_id: 0,
+            reward_pools: vec![],
+            seeds: vec![banana_seed],
+            metadata,
+            total_lock_boost: 0,
+            max_lock_duration: DEFAULT_MAX_LOCK_DURATION,
+            max_lock_boost_bps: DEFAULT_MAX_LOCK_BOOST_BPS,
+        };
+        this.measure_account_storage_usage();
+        write_state_version();
+        this
     }
 
+    /// Adds the attached deposit to the reward stream. The stream pays out linearly to the
+    /// cucumber holders over the reward stream duration.
+    /// When there are not enough cucumbers, the deposit waits in the undistributed pool until
+    /// the next stake or `distribute_pending`.
     #[payable]
     pub fn take_my_near(&mut self) {
-        assert!(
-            self.total_cucumber_balance >= NEAR_PER_CUCUMBER_DENOM,
-            "Not enough cucumbers"
-        );
+        self.assert_reward_deposits_not_paused();
         let attached_deposit = env::attached_deposit();
-        let near_per_cucumber = (U256::from(attached_deposit)
-            * U256::from(NEAR_PER_CUCUMBER_DENOM)
-            / U256::from(self.total_cucumber_balance))
-        .as_u128();
-        self.near_per_cucumber_numer += near_per_cucumber;
+        if self.total_weighted_balance() >= NEAR_PER_CUCUMBER_DENOM {
+            self.add_to_reward_stream(attached_deposit);
+        } else {
+            self.undistributed_near += attached_deposit;
+        }
         self.total_near_received += attached_deposit;
+        emit_farm_event(
+            "reward_deposit",
+            json!({
+                "sender_id": env::predecessor_account_id(),
+                "amount": U128(attached_deposit),
+            }),
+        );
     }
 
+    /// Registers the predecessor with the minimum storage deposit. The rest of the attached
+    /// deposit is refunded.
+    #[payable]
     pub fn register_account(&mut self) {
-        let (account_id_hash, account) = self.get_mut_account(&env::predecessor_account_id());
-        self.save_account(&account_id_hash, &account);
+        self.storage_deposit(None, Some(true));
     }
 
     pub fn account_exists(&self, account_id: ValidAccountId) -> bool {
         self.get_internal_account(account_id.as_ref()).1.is_some()
     }
 
-    pub fn claim_near(&mut self) -> U128 {
-        let account_id = env::predecessor_account_id();
-        let (account_id_hash, mut account) = self.get_mut_account(&account_id);
-        let amount = account.near_balance;
-        account.near_balance = 0;
-        account.near_claimed += amount;
-        self.save_account(&account_id_hash, &account);
-        if amount > 0 {
-            Promise::new(account_id).transfer(amount);
-            self.total_near_claimed += amount;
-        }
-        amount.into()
-    }
-
     pub fn get_near_balance(&self, account_id: ValidAccountId) -> U128 {
         self.get_internal_account(account_id.as_ref())
             .1
@@ -181,10 +494,26 @@ impl Farm {
             .1
             .map(|mut account| {
                 self.touch(&mut account);
+                let unbonding = self.human_unbonding(&account);
+                let token_rewards = self.human_token_rewards(&account);
+                let locked_balance = account.locked_cucumber_balance();
+                let weighted_balance = account.weighted_balance();
                 HumanAccount {
                     near_balance: account.near_balance.into(),
                     cucumber_balance: account.cucumber_balance.into(),
                     near_claimed: account.near_claimed.into(),
+                    unbonding,
+                    unbonding_release_timestamp: account.unbonding_release_timestamp.into(),
+                    banana_balance: account.banana_balance.into(),
+                    banana_claimed: account.banana_claimed.into(),
+                    reward_beneficiary: account.reward_beneficiary,
+                    reward_split: account.reward_split,
+                    auto_claim_threshold: account.auto_claim_threshold.map(|amount| amount.into()),
+                    token_rewards,
+                    locked_balance: locked_balance.into(),
+                    lock_end_timestamp: account.lock_end_timestamp.into(),
+                    weighted_balance: weighted_balance.into(),
+                    wrapped_near_balance: account.wrapped_near_balance.into(),
                 }
             })
     }
@@ -192,8 +521,11 @@ impl Farm {
     pub fn get_stats(&self) -> HumanStats {
         HumanStats {
             total_cucumber_balance: self.total_cucumber_balance.into(),
+            total_weighted_balance: self.total_weighted_balance().into(),
             total_near_claimed: self.total_near_claimed.into(),
             total_near_received: self.total_near_received.into(),
+            total_banana_claimed: self.total_banana_claimed.into(),
+            total_banana_received: self.total_banana_received.into(),
         }
     }
 
@@ -204,6 +536,14 @@ impl Farm {
     pub fn get_total_near_received(&self) -> U128 {
         self.total_near_received.into()
     }
+
+    pub fn get_unbonding_period(&self) -> WrappedDuration {
+        self.unbonding_period.into()
+    }
+
+    pub fn get_instant_unstake_penalty_bps(&self) -> u16 {
+        self.instant_unstake_penalty_bps
+    }
 }
 
 #[near_bindgen]
@@ -215,31 +555,37 @@ impl VaultFungibleTokenReceiver for Farm {
         vault_id: VaultId,
         payload: String,
     ) -> Promise {
-        if &env::predecessor_account_id() != &self.banana_token_account_id {
-            env::panic(b"This farm can only receive bananas through a contract API");
-        }
+        let seed_index = self.predecessor_seed_index();
+        self.assert_staking_not_paused();
         let payload: OnReceiverPayload =
             serde_json::from_str(&payload).expect("Failed to parse the payload");
 
         let amount: Balance = amount.into();
 
         match payload {
-            OnReceiverPayload::DepositAndStake => {
-                let (account_id_hash, mut account) = self.get_mut_account(sender_id.as_ref());
-                account.cucumber_balance += amount;
-                self.save_account(&account_id_hash, &account);
-
-                self.total_cucumber_balance += amount;
+            OnReceiverPayload::DepositAndStake | OnReceiverPayload::DepositAndLock { .. } => {
+                let cucumber_amount = self.stake_seed(seed_index, sender_id.as_ref(), amount);
+                if let OnReceiverPayload::DepositAndLock { duration } = payload {
+                    self.lock_cucumbers(
+                        sender_id.as_ref(),
+                        seed_index,
+                        cucumber_amount,
+                        duration.into(),
+                    );
+                }
 
                 ext_token::withdraw_from_vault(
                     vault_id,
                     env::current_account_id(),
                     amount.into(),
-                    &self.banana_token_account_id,
+                    &self.seeds[seed_index].token_id,
                     NO_DEPOSIT,
                     GAS_FOR_WITHDRAW_FROM_VAULT,
                 )
             }
+            OnReceiverPayload::DepositReward => {
+                env::panic(b"Reward tokens can only be deposited with ft_transfer_call")
+            }
         }
     }
 }
@@ -247,35 +593,143 @@ impl VaultFungibleTokenReceiver for Farm {
 impl Farm {
     fn get_internal_account(&self, account_id: &AccountId) -> (ShortAccountHash, Option<Account>) {
         let account_id_hash: ShortAccountHash = account_id.into();
-        let account = self.accounts.get(&account_id_hash);
+        let account = self.accounts.get(&account_id_hash).or_else(|| {
+            self.accounts_v0
+                .get(&account_id_hash)
+                .map(|account| self.migrate_account_v0(account))
+        });
         (account_id_hash, account)
     }
 
     /// Redeeming rewards and updating inner pool balances.
     fn touch(&self, account: &mut Account) {
-        let near_per_cucumber_diff =
-            self.near_per_cucumber_numer - account.last_near_per_cucumber_numer;
-        let earned_balance = (U256::from(near_per_cucumber_diff)
-            * U256::from(account.cucumber_balance)
+        let near_per_cucumber_numer = self.current_near_per_cucumber_numer();
+        let near_per_cucumber_diff = near_per_cucumber_numer - account.last_near_per_cucumber_numer;
+        let earned_balance = U256::from(near_per_cucumber_diff)
+            * U256::from(account.weighted_balance())
+            + U256::from(account.near_balance_remainder);
+        let denom = U256::from(NEAR_PER_CUCUMBER_DENOM);
+        account.near_balance += (earned_balance / denom).as_u128();
+        account.near_balance_remainder = (earned_balance % denom).as_u128();
+        account.last_near_per_cucumber_numer = near_per_cucumber_numer;
+
+        let banana_per_cucumber_diff =
+            self.banana_per_cucumber_numer - account.last_banana_per_cucumber_numer;
+        let earned_banana_balance = (U256::from(banana_per_cucumber_diff)
+            * U256::from(account.weighted_balance())
             / U256::from(NEAR_PER_CUCUMBER_DENOM))
         .as_u128();
-        account.near_balance += earned_balance;
-        account.last_near_per_cucumber_numer = self.near_per_cucumber_numer;
+        account.banana_balance += earned_banana_balance;
+        account.last_banana_per_cucumber_numer = self.banana_per_cucumber_numer;
+
+        self.touch_token_rewards(account);
     }
 
-    fn get_mut_account(&mut self, account_id: &AccountId) -> (ShortAccountHash, Account) {
-        let (account_id_hash, account) = self.get_internal_account(&account_id);
-        let mut account = account.unwrap_or_else(|| Account {
-            last_near_per_cucumber_numer: self.near_per_cucumber_numer,
+    fn new_account(&self) -> Account {
+        Account {
+            last_near_per_cucumber_numer: self.current_near_per_cucumber_numer(),
             near_balance: 0,
             cucumber_balance: 0,
             near_claimed: 0,
-        });
+            near_balance_remainder: 0,
+            unbonding_balances: vec![0; MAX_SEEDS],
+            unbonding_release_timestamp: 0,
+            last_banana_per_cucumber_numer: self.banana_per_cucumber_numer,
+            banana_balance: 0,
+            banana_claimed: 0,
+            storage_balance: 0,
+            storage_usage: 0,
+            reward_beneficiary: None,
+            reward_split: vec![],
+            auto_claim_threshold: None,
+            token_rewards: vec![TokenReward::default(); MAX_REWARD_TOKENS],
+            locked_seed_balances: vec![0; MAX_SEEDS],
+            lock_end_timestamp: 0,
+            lock_boost: 0,
+            storage_credit: 0,
+            seed_cucumber_balances: vec![0; MAX_SEEDS],
+            wrapped_near_balance: 0,
+        }
+    }
+
+    /// Returns the touched account. Panics if the account is not registered.
+    fn get_mut_account(&mut self, account_id: &AccountId) -> (ShortAccountHash, Account) {
+        self.advance_reward_stream();
+        let (account_id_hash, account) = self.get_internal_account(account_id);
+        let mut account = account.expect("The account is not registered");
         self.touch(&mut account);
         (account_id_hash, account)
     }
 
-    fn save_account(&mut self, account_id_hash: &ShortAccountHash, account: &Account) {
+    /// Saves the account and updates its measured storage usage. Panics if the storage deposit
+    /// of the account doesn't cover the new storage usage.
+    /// The boost of the expired lock is removed, the account should be touched before.
+    fn save_account(&mut self, account_id_hash: &ShortAccountHash, account: &mut Account) {
+        self.release_expired_lock(account);
+        if account.storage_usage == 0 {
+            // The account is either new or upgraded from the old layout.
+            self.accounts_v0.remove(account_id_hash);
+        }
+        let initial_storage_usage = env::storage_usage();
         self.accounts.insert(account_id_hash, account);
+        let storage_usage = env::storage_usage();
+        if storage_usage != initial_storage_usage {
+            account.storage_usage = account.storage_usage + storage_usage - initial_storage_usage;
+            // The storage usage is a fixed size field, so saving it again doesn't change the size.
+            self.accounts.insert(account_id_hash, account);
+            if account.storage_usage as Balance * env::storage_byte_cost() > account.storage_balance
+            {
+                env::panic(b"Not enough storage balance. Attach more with storage_deposit");
+            }
+        }
+    }
+
+    fn remove_account(&mut self, account_id_hash: &ShortAccountHash) {
+        self.accounts.remove(account_id_hash);
+        self.accounts_v0.remove(account_id_hash);
+    }
+
+    fn measure_account_storage_usage(&mut self) {
+        let initial_storage_usage = env::storage_usage();
+        let tmp_account_id_hash = ShortAccountHash([0u8; 20]);
+        self.accounts
+            .insert(&tmp_account_id_hash, &self.new_account());
+        self.account_storage_usage = env::storage_usage() - initial_storage_usage;
+        self.accounts.remove(&tmp_account_id_hash);
+    }
+}
+
+#[cfg(test)]
+mod test_utils {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, MockedBlockchain, PromiseResult};
+
+    pub const ONE_NEAR: Balance = 1_000_000_000_000_000_000_000_000;
+
+    /// Sets the context of a call to the farm on `accounts(0)`.
+    pub fn set_context(predecessor: ValidAccountId, timestamp: Timestamp, deposit: Balance) {
+        let context = VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(predecessor)
+            .block_timestamp(timestamp)
+            .attached_deposit(deposit)
+            .build();
+        testing_env!(context);
+    }
+
+    /// Sets the context of a farm callback with the result of the promise it's attached to.
+    pub fn set_callback_context(result: PromiseResult) {
+        let context = VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .build();
+        testing_env!(
+            context,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![result]
+        );
     }
 }
@@ -0,0 +1,130 @@
+//! Time-locked staking. Seeds deposited with the `DepositAndLock` payload mint cucumbers that are
+//! locked until the lock ends. The locked cucumbers can't be transferred or unstaked, and they get
+//! a boosted weight in the reward distribution, growing linearly with the lock duration up to
+//! `max_lock_boost_bps` for `max_lock_duration`.
+//! Every account has a single lock end. The locked cucumbers are tracked by the seed they are
+//! minted for, so only the cucumbers of the other seeds or the unlocked ones can leave the account.
+//! The extra weight of the lock is added to `total_lock_boost`,
+//! and the rewards are distributed by `total_weighted_balance`. The boost is removed when the
+//! expired lock account is saved, or with `release_lock`.
+use crate::*;
+use near_sdk::assert_one_yocto;
+
+/// 365 days in nanoseconds.
+pub const DEFAULT_MAX_LOCK_DURATION: Duration = 365 * 24 * 60 * 60 * 1_000_000_000;
+
+/// The extra weight of the cucumbers locked for the maximum duration, 2x in total.
+pub const DEFAULT_MAX_LOCK_BOOST_BPS: u16 = 10_000;
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HumanLockConfig {
+    pub max_lock_duration: WrappedDuration,
+    pub max_lock_boost_bps: u16,
+    pub total_lock_boost: U128,
+}
+
+#[near_bindgen]
+impl Farm {
+    /// Changes the maximum lock duration and the boost for it. The existing locks keep their
+    /// boost.
+    #[payable]
+    pub fn set_lock_config(&mut self, max_lock_duration: WrappedDuration, max_lock_boost_bps: u16) {
+        assert_one_yocto();
+        self.assert_owner();
+        let max_lock_duration: Duration = max_lock_duration.into();
+        if max_lock_duration == 0 {
+            env::panic(b"The max lock duration should be positive");
+        }
+        self.max_lock_duration = max_lock_duration;
+        self.max_lock_boost_bps = max_lock_boost_bps;
+        emit_farm_event(
+            "lock_config_update",
+            json!({
+                "max_lock_duration": WrappedDuration::from(max_lock_duration),
+                "max_lock_boost_bps": max_lock_boost_bps,
+            }),
+        );
+    }
+
+    /// Removes the boost of the account's expired lock. Can be called by anyone, so the boost
+    /// doesn't outlive the lock of an inactive account.
+    pub fn release_lock(&mut self, account_id: ValidAccountId) {
+        let (account_id_hash, mut account) = self.get_mut_account(account_id.as_ref());
+        if account.lock_boost == 0 || env::block_timestamp() < account.lock_end_timestamp {
+            env::panic(b"The account has no expired lock");
+        }
+        self.save_account(&account_id_hash, &mut account);
+    }
+
+    pub fn get_lock_config(&self) -> HumanLockConfig {
+        HumanLockConfig {
+            max_lock_duration: self.max_lock_duration.into(),
+            max_lock_boost_bps: self.max_lock_boost_bps,
+            total_lock_boost: self.total_lock_boost.into(),
+        }
+    }
+}
+
+impl Farm {
+    /// The total balance the rewards are distributed by, the cucumbers with the lock boosts.
+    pub(crate) fn total_weighted_balance(&self) -> Balance {
+        self.total_cucumber_balance + self.total_lock_boost
+    }
+
+    /// Locks the newly minted cucumbers of the seed together with the currently locked cucumbers
+    /// of the account. The new lock can't end before the current one.
+    pub(crate) fn lock_cucumbers(
+        &mut self,
+        account_id: &AccountId,
+        seed_index: usize,
+        cucumber_amount: Balance,
+        duration: Duration,
+    ) {
+        if duration == 0 || duration > self.max_lock_duration {
+            env::panic(b"The lock duration is invalid");
+        }
+        let lock_end_timestamp = env::block_timestamp() + duration;
+        let lock_boost_bps = (U256::from(self.max_lock_boost_bps) * U256::from(duration)
+            / U256::from(self.max_lock_duration))
+        .as_u128();
+        let (account_id_hash, mut account) = self.get_mut_account(account_id);
+        if lock_end_timestamp < account.lock_end_timestamp {
+            env::panic(b"The lock can't end before the current lock");
+        }
+        if account.locked_cucumber_balance() == 0 {
+            // The expired lock is replaced.
+            account.locked_seed_balances = vec![0; MAX_SEEDS];
+        }
+        account.locked_seed_balances[seed_index] += cucumber_amount;
+        let locked_balance: Balance = account.locked_seed_balances.iter().sum();
+        let lock_boost = (U256::from(locked_balance) * U256::from(lock_boost_bps)
+            / U256::from(MAX_BASIS_POINTS))
+        .as_u128();
+        self.total_lock_boost = self.total_lock_boost - account.lock_boost + lock_boost;
+        account.lock_end_timestamp = lock_end_timestamp;
+        account.lock_boost = lock_boost;
+        self.save_account(&account_id_hash, &mut account);
+        emit_farm_event(
+            "lock",
+            json!({
+                "account_id": account_id,
+                "token_id": self.seeds[seed_index].token_id,
+                "amount": U128(cucumber_amount),
+                "locked_balance": U128(locked_balance),
+                "lock_end_timestamp": WrappedTimestamp::from(lock_end_timestamp),
+                "lock_boost": U128(lock_boost),
+            }),
+        );
+    }
+
+    /// Removes the boost of the expired lock, part of `save_account`.
+    pub(crate) fn release_expired_lock(&mut self, account: &mut Account) {
+        if account.lock_boost > 0 && env::block_timestamp() >= account.lock_end_timestamp {
+            self.advance_reward_stream();
+            self.total_lock_boost -= account.lock_boost;
+            account.lock_boost = 0;
+            account.locked_seed_balances = vec![0; MAX_SEEDS];
+        }
+    }
+}
@@ -0,0 +1,330 @@
+//! State versioning. The version of the `Farm` layout is stored under a separate key, so `migrate`
+//! knows how to read the old state before it can be deserialized.
+//! Accounts and vaults of the older layouts stay under their old prefixes and are upgraded lazily
+//! on the first access.
+use crate::*;
+
+const STATE_VERSION_KEY: &[u8] = b"STATE_VERSION";
+
+/// The version of the current `Farm`, `Account` and `Vault` layouts. Any change of these layouts
+/// should bump the version, freeze the previous layout and add its migration path.
+pub const STATE_VERSION: u32 = 1;
+
+pub(crate) const ACCOUNTS_V0_PREFIX: &[u8] = b"a";
+pub(crate) const VAULTS_V0_PREFIX: &[u8] = b"v";
+pub(crate) const ACCOUNTS_PREFIX: &[u8] = b"a1";
+pub(crate) const VAULTS_PREFIX: &[u8] = b"v1";
+pub(crate) const CLAIM_CALLS_PREFIX: &[u8] = b"c";
+
+/// The layout of the account before the state versioning.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct AccountV0 {
+    pub last_near_per_cucumber_numer: Balance,
+    pub near_balance: Balance,
+    pub cucumber_balance: Balance,
+    pub near_claimed: Balance,
+}
+
+/// The layout of the vault before the state versioning.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct VaultV0 {
+    pub receiver_id_hash: ShortAccountHash,
+    pub balance: Balance,
+}
+
+impl From<VaultV0> for Vault {
+    fn from(vault: VaultV0) -> Self {
+        Self {
+            receiver_id_hash: vault.receiver_id_hash,
+            balance: vault.balance,
+            storage_balance: 0,
+            seed_balances: banana_seed_balances(vault.balance),
+        }
+    }
+}
+
+/// The layout of the farm before the state versioning.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct FarmV0 {
+    pub accounts: LookupMap<ShortAccountHash, AccountV0>,
+    pub banana_token_account_id: AccountId,
+    pub near_per_cucumber_numer: Balance,
+    pub total_cucumber_balance: Balance,
+    pub total_near_received: Balance,
+    pub total_near_claimed: Balance,
+    pub vaults: LookupMap<VaultId, VaultV0>,
+    pub next_vault_id: VaultId,
+}
+
+#[near_bindgen]
+impl Farm {
+    /// Upgrades the state written by any previous version of the contract.
+    /// Removes the staged upgrade, since it's the code being migrated to, and refunds its storage
+    /// deposit.
+    #[init(ignore_state)]
+    #[private]
+    pub fn migrate() -> Self {
+        let farm = match read_state_version() {
+            0 => {
+                let farm: FarmV0 = env::state_read().expect("Failed to read the state");
+                Self::migrate_farm_v0(farm)
+            }
+            STATE_VERSION => {
+                let mut farm: Self = env::state_read().expect("Failed to read the state");
+                if let Some(staged_upgrade) = farm.staged_upgrade.take() {
+                    staged_upgrade.refund_storage_deposit();
+                }
+                farm
+            }
+            _ => env::panic(b"Unknown state version"),
+        };
+        remove_staged_code();
+        write_state_version();
+        farm
+    }
+
+    pub fn get_state_version(&self) -> u32 {
+        read_state_version()
+    }
+}
+
+impl Farm {
+    /// Upgrades the farm of the old layout. The old accounts and vaults stay under their prefixes
+    /// and are upgraded lazily. The farms without the owner get the farm account itself as the
+    /// owner.
+    fn migrate_farm_v0(farm: FarmV0) -> Self {
+        let banana_seed = Seed {
+            total_staked: farm.total_cucumber_balance,
+            total_cucumber_balance: farm.total_cucumber_balance,
+            ..Seed::new(
+                farm.banana_token_account_id.clone(),
+                CUCUMBER_DECIMALS,
+                DEFAULT_MINT_RATIO_BPS,
+            )
+        };
+        let mut farm = Self {
+            accounts: LookupMap::new(ACCOUNTS_PREFIX.to_vec()),
+            accounts_v0: farm.accounts,
+            banana_token_account_id: farm.banana_token_account_id,
+            near_per_cucumber_numer: farm.near_per_cucumber_numer,
+            total_cucumber_balance: farm.total_cucumber_balance,
+            total_near_received: farm.total_near_received,
+            total_near_claimed: farm.total_near_claimed,
+            vaults: LookupMap::new(VAULTS_PREFIX.to_vec()),
+            vaults_v0: farm.vaults,
+            next_vault_id: farm.next_vault_id,
+            unbonding_period: DEFAULT_UNBONDING_PERIOD,
+            instant_unstake_penalty_bps: DEFAULT_INSTANT_UNSTAKE_PENALTY_BPS,
+            banana_per_cucumber_numer: 0,
+            total_banana_received: 0,
+            total_banana_claimed: 0,
+            account_storage_usage: 0,
+            owner_id: env::current_account_id(),
+            pause_status: PauseStatus::default(),
+            upgrade_delay: DEFAULT_UPGRADE_DELAY,
+            staged_upgrade: None,
+            reward_stream_duration: DEFAULT_REWARD_STREAM_DURATION,
+            reward_stream_balance: 0,
+            reward_stream_end_timestamp: 0,
+            reward_stream_last_timestamp: env::block_timestamp(),
+            undistributed_near: 0,
+            near_distribution_dust: 0,
+            // The old farm distributed the deposits instantly.
+            total_near_distributed: farm.total_near_received,
+            wrap_near_account_id: None,
+            total_wrapped_near_owed: 0,
+            claim_calls: LookupMap::new(CLAIM_CALLS_PREFIX.to_vec()),
+            next_claim_call_id: 0,
+            reward_pools: vec![],
+            seeds: vec![banana_seed],
+            metadata: None,
+            total_lock_boost: 0,
+            max_lock_duration: DEFAULT_MAX_LOCK_DURATION,
+            max_lock_boost_bps: DEFAULT_MAX_LOCK_BOOST_BPS,
+        };
+        farm.measure_account_storage_usage();
+        farm
+    }
+
+    /// Upgrades the account of the old layout. The farm covers the storage of such accounts,
+    /// because they were created without a measured storage deposit. The cucumbers were minted
+    /// for bananas.
+    pub(crate) fn migrate_account_v0(&self, account: AccountV0) -> Account {
+        Account {
+            seed_cucumber_balances: banana_seed_balances(account.cucumber_balance),
+            last_near_per_cucumber_numer: account.last_near_per_cucumber_numer,
+            near_balance: account.near_balance,
+            cucumber_balance: account.cucumber_balance,
+            near_claimed: account.near_claimed,
+            // The banana penalties started at 0 with the migration, and the old cucumbers earn
+            // their share of them.
+            last_banana_per_cucumber_numer: 0,
+            storage_balance: self.account_storage_usage as Balance * env::storage_byte_cost(),
+            // The old accounts were registered for free, so the farm covers their storage.
+            storage_credit: self.account_storage_usage as Balance * env::storage_byte_cost(),
+            ..self.new_account()
+        }
+    }
+}
+
+/// Returns the balances by the seed index with the whole balance in the banana slot.
+fn banana_seed_balances(balance: Balance) -> Vec<Balance> {
+    let mut seed_balances = vec![0; MAX_SEEDS];
+    seed_balances[BANANA_SEED_INDEX] = balance;
+    seed_balances
+}
+
+pub(crate) fn read_state_version() -> u32 {
+    env::storage_read(STATE_VERSION_KEY)
+        .map(|value| u32::try_from_slice(&value).expect("Failed to read the state version"))
+        .unwrap_or(0)
+}
+
+pub(crate) fn write_state_version() {
+    env::storage_write(STATE_VERSION_KEY, &STATE_VERSION.try_to_vec().unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::test_utils::accounts;
+    use near_sdk::PromiseResult;
+
+    fn write_farm_v0() {
+        let mut farm = FarmV0 {
+            accounts: LookupMap::new(ACCOUNTS_V0_PREFIX.to_vec()),
+            banana_token_account_id: accounts(1).into(),
+            near_per_cucumber_numer: 3,
+            total_cucumber_balance: 10 * NEAR_PER_CUCUMBER_DENOM,
+            total_near_received: 30,
+            total_near_claimed: 5,
+            vaults: LookupMap::new(VAULTS_V0_PREFIX.to_vec()),
+            next_vault_id: VaultId(7),
+        };
+        farm.accounts.insert(
+            &accounts(2).as_ref().into(),
+            &AccountV0 {
+                last_near_per_cucumber_numer: 1,
+                near_balance: 5,
+                cucumber_balance: 10 * NEAR_PER_CUCUMBER_DENOM,
+                near_claimed: 5,
+            },
+        );
+        farm.vaults.insert(
+            &VaultId(6),
+            &VaultV0 {
+                receiver_id_hash: accounts(3).as_ref().into(),
+                balance: 4,
+            },
+        );
+        env::state_write(&farm);
+    }
+
+    #[test]
+    fn test_migrate_farm_v0() {
+        set_context(accounts(0), 0, 0);
+        write_farm_v0();
+
+        let farm = Farm::migrate();
+        assert_eq!(read_state_version(), STATE_VERSION);
+        assert_eq!(farm.banana_token_account_id, accounts(1).to_string());
+        assert_eq!(farm.near_per_cucumber_numer, 3);
+        assert_eq!(farm.total_cucumber_balance, 10 * NEAR_PER_CUCUMBER_DENOM);
+        assert_eq!(farm.total_near_received, 30);
+        assert_eq!(farm.total_near_claimed, 5);
+        assert_eq!(farm.next_vault_id.0, 7);
+        assert_eq!(farm.owner_id, accounts(0).to_string());
+        assert_eq!(farm.unbonding_period, DEFAULT_UNBONDING_PERIOD);
+        assert_eq!(
+            farm.seeds[BANANA_SEED_INDEX].total_staked,
+            10 * NEAR_PER_CUCUMBER_DENOM
+        );
+        assert!(farm.account_storage_usage > 0);
+    }
+
+    #[test]
+    fn test_migrate_account_v0() {
+        set_context(accounts(0), 0, 0);
+        write_farm_v0();
+
+        let mut farm = Farm::migrate();
+        // The instant unstake penalty is shared with the old cucumbers before the account is
+        // upgraded.
+        set_context(accounts(3), 0, ONE_NEAR);
+        farm.storage_deposit(None, None);
+        set_context(accounts(1), 0, 0);
+        farm.ft_on_transfer(
+            accounts(3),
+            (10 * NEAR_PER_CUCUMBER_DENOM).into(),
+            "\"DepositAndStake\"".to_string(),
+        );
+        set_context(accounts(3), 0, 1);
+        farm.instant_unstake((10 * NEAR_PER_CUCUMBER_DENOM).into());
+        set_callback_context(PromiseResult::Successful(vec![]));
+        farm.resolve_instant_unstake(
+            accounts(3).into(),
+            (9 * NEAR_PER_CUCUMBER_DENOM).into(),
+            NEAR_PER_CUCUMBER_DENOM.into(),
+            (10 * NEAR_PER_CUCUMBER_DENOM).into(),
+        );
+
+        let account = farm.get_account(accounts(2)).unwrap();
+        assert_eq!(account.cucumber_balance.0, 10 * NEAR_PER_CUCUMBER_DENOM);
+        // 5 unclaimed plus 20 earned since the last touch.
+        assert_eq!(account.near_balance.0, 25);
+        assert_eq!(account.near_claimed.0, 5);
+        assert_eq!(account.banana_balance.0, NEAR_PER_CUCUMBER_DENOM);
+
+        let (account_id_hash, mut account) = farm.get_mut_account(accounts(2).as_ref());
+        farm.save_account(&account_id_hash, &mut account);
+        assert!(farm.accounts_v0.get(&account_id_hash).is_none());
+        let account = farm.accounts.get(&account_id_hash).unwrap();
+        assert_eq!(
+            account.seed_cucumber_balances[BANANA_SEED_INDEX],
+            10 * NEAR_PER_CUCUMBER_DENOM
+        );
+        assert_eq!(farm.storage_balance_of(accounts(2)).unwrap().available.0, 0);
+    }
+
+    #[test]
+    fn test_migrate_vault_v0() {
+        set_context(accounts(0), 0, 0);
+        write_farm_v0();
+
+        let mut farm = Farm::migrate();
+        let vault = farm.remove_vault(&VaultId(6)).unwrap();
+        assert_eq!(vault.balance, 4);
+        assert_eq!(vault.storage_balance, 0);
+        assert_eq!(vault.seed_balances[BANANA_SEED_INDEX], 4);
+        assert!(farm.remove_vault(&VaultId(6)).is_none());
+    }
+
+    #[test]
+    fn test_migrate_current_version() {
+        set_context(accounts(0), 0, 0);
+        let mut farm = Farm::new(accounts(1), accounts(2), None, None, None, None);
+        farm.staged_upgrade = Some(StagedUpgrade {
+            code_hash: [0; 32],
+            activation_timestamp: 0,
+            owner_id: accounts(2).into(),
+            storage_deposit: ONE_NEAR,
+        });
+        env::storage_write(b"STAGED_CODE", b"code");
+        env::state_write(&farm);
+        let receipt_count = near_sdk::test_utils::get_created_receipts().len();
+
+        let farm = Farm::migrate();
+        assert_eq!(farm.owner_id, accounts(2).to_string());
+        assert_eq!(read_state_version(), STATE_VERSION);
+        assert!(farm.staged_upgrade.is_none());
+        assert!(!env::storage_has_key(b"STAGED_CODE"));
+        // The refund of the storage deposit.
+        assert_eq!(
+            near_sdk::test_utils::get_created_receipts().len(),
+            receipt_count + 1
+        );
+    }
+}
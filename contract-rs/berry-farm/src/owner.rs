@@ -0,0 +1,53 @@
+use crate::*;
+use near_sdk::assert_one_yocto;
+
+#[near_bindgen]
+impl Farm {
+    #[payable]
+    pub fn set_owner(&mut self, owner_id: ValidAccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.owner_id = owner_id.into();
+        emit_farm_event(
+            "owner_update",
+            json!({
+                "owner_id": self.owner_id,
+            }),
+        );
+    }
+
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    /// Changes the unbonding period for the future unstakes.
+    /// The bananas that are already unbonding keep their release timestamps.
+    #[payable]
+    pub fn set_unbonding_period(&mut self, unbonding_period: WrappedDuration) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.unbonding_period = unbonding_period.into();
+    }
+
+    #[payable]
+    pub fn set_instant_unstake_penalty_bps(&mut self, instant_unstake_penalty_bps: u16) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert_valid_penalty_bps(instant_unstake_penalty_bps);
+        self.instant_unstake_penalty_bps = instant_unstake_penalty_bps;
+    }
+}
+
+impl Farm {
+    pub(crate) fn assert_owner(&self) {
+        if env::predecessor_account_id() != self.owner_id {
+            env::panic(b"This method can only be called by the owner");
+        }
+    }
+}
+
+pub(crate) fn assert_valid_penalty_bps(penalty_bps: u16) {
+    if penalty_bps >= MAX_BASIS_POINTS {
+        env::panic(b"The penalty should be less than 100%");
+    }
+}
@@ -0,0 +1,69 @@
+use crate::*;
+use near_sdk::assert_one_yocto;
+
+/// Which parts of the farm are currently stopped.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PauseStatus {
+    /// Staking with `ft_on_transfer` and `on_receive_with_vault`. Unstaking is never paused, so
+    /// the stakers can always exit.
+    pub staking: bool,
+    /// Cucumber transfers, including transfers with vaults.
+    pub transfers: bool,
+    /// NEAR reward deposits with `take_my_near`.
+    pub reward_deposits: bool,
+    /// Claims of NEAR and bananas, including withdrawals of unbonded bananas.
+    pub claims: bool,
+}
+
+#[near_bindgen]
+impl Farm {
+    /// Updates the given pause flags. The flags that are not given are left unchanged.
+    #[payable]
+    pub fn set_pause_status(
+        &mut self,
+        staking: Option<bool>,
+        transfers: Option<bool>,
+        reward_deposits: Option<bool>,
+        claims: Option<bool>,
+    ) {
+        assert_one_yocto();
+        self.assert_owner();
+        let pause_status = &mut self.pause_status;
+        pause_status.staking = staking.unwrap_or(pause_status.staking);
+        pause_status.transfers = transfers.unwrap_or(pause_status.transfers);
+        pause_status.reward_deposits = reward_deposits.unwrap_or(pause_status.reward_deposits);
+        pause_status.claims = claims.unwrap_or(pause_status.claims);
+        emit_farm_event("pause_update", json!(self.pause_status));
+    }
+
+    pub fn get_pause_status(&self) -> &PauseStatus {
+        &self.pause_status
+    }
+}
+
+impl Farm {
+    pub(crate) fn assert_staking_not_paused(&self) {
+        if self.pause_status.staking {
+            env::panic(b"Staking is paused");
+        }
+    }
+
+    pub(crate) fn assert_transfers_not_paused(&self) {
+        if self.pause_status.transfers {
+            env::panic(b"Transfers are paused");
+        }
+    }
+
+    pub(crate) fn assert_reward_deposits_not_paused(&self) {
+        if self.pause_status.reward_deposits {
+            env::panic(b"Reward deposits are paused");
+        }
+    }
+
+    pub(crate) fn assert_claims_not_paused(&self) {
+        if self.pause_status.claims {
+            env::panic(b"Claims are paused");
+        }
+    }
+}
@@ -0,0 +1,312 @@
+//! NEAR rewards are not distributed at once. Every deposit goes into a stream that releases it
+//! linearly into `near_per_cucumber_numer` until the end of the stream.
+use crate::*;
+use near_sdk::assert_one_yocto;
+
+/// 24 hours in nanoseconds.
+pub const DEFAULT_REWARD_STREAM_DURATION: Duration = 24 * 60 * 60 * 1_000_000_000;
+
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HumanRewardStream {
+    /// The NEAR that is not released yet.
+    pub balance: U128,
+    /// The NEAR released per second.
+    pub reward_rate: U128,
+    pub end_timestamp: WrappedTimestamp,
+    pub duration: WrappedDuration,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HumanNearReconciliation {
+    pub total_near_received: U128,
+    pub total_near_claimed: U128,
+    /// The distributed NEAR that is not claimed yet, including the sub-yoctoNEAR remainders
+    /// carried by the accounts.
+    pub outstanding_near: U128,
+    /// The released NEAR that is carried to the next distribution.
+    pub near_distribution_dust: U128,
+    pub undistributed_near: U128,
+    /// The NEAR of the stream that is not released yet.
+    pub reward_stream_balance: U128,
+}
+
+#[near_bindgen]
+impl Farm {
+    /// Changes the duration of the future reward streams. A zero duration distributes the
+    /// deposits instantly.
+    #[payable]
+    pub fn set_reward_stream_duration(&mut self, reward_stream_duration: WrappedDuration) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.reward_stream_duration = reward_stream_duration.into();
+    }
+
+    /// Moves the undistributed NEAR into the reward stream once there are enough cucumbers.
+    /// Returns the amount that was moved.
+    pub fn distribute_pending(&mut self) -> U128 {
+        self.advance_reward_stream();
+        self.distribute_undistributed_near().into()
+    }
+
+    pub fn get_undistributed_near(&self) -> U128 {
+        self.undistributed_near.into()
+    }
+
+    /// Accounts for every NEAR the farm has received. Always holds
+    /// `total_near_received = total_near_claimed + outstanding_near + near_distribution_dust +
+    /// undistributed_near + reward_stream_balance`.
+    pub fn get_near_reconciliation(&self) -> HumanNearReconciliation {
+        let mut total_near_distributed = self.total_near_distributed;
+        let mut near_distribution_dust = self.near_distribution_dust;
+        let released_balance = self.released_reward_stream_balance();
+        if self.total_weighted_balance() >= NEAR_PER_CUCUMBER_DENOM {
+            let distributed_balance = self.split_distribution(released_balance).1;
+            total_near_distributed += distributed_balance;
+            near_distribution_dust =
+                released_balance + near_distribution_dust - distributed_balance;
+        }
+        HumanNearReconciliation {
+            total_near_received: self.total_near_received.into(),
+            total_near_claimed: self.total_near_claimed.into(),
+            outstanding_near: (total_near_distributed - self.total_near_claimed).into(),
+            near_distribution_dust: near_distribution_dust.into(),
+            undistributed_near: self.undistributed_near.into(),
+            reward_stream_balance: (self.reward_stream_balance - released_balance).into(),
+        }
+    }
+
+    pub fn get_reward_stream(&self) -> HumanRewardStream {
+        let timestamp = env::block_timestamp();
+        let balance = self.reward_stream_balance - self.released_reward_stream_balance();
+        let reward_rate = if self.reward_stream_end_timestamp > timestamp {
+            (U256::from(balance) * U256::from(NANOSECONDS_PER_SECOND)
+                / U256::from(self.reward_stream_end_timestamp - timestamp))
+            .as_u128()
+        } else {
+            0
+        };
+        HumanRewardStream {
+            balance: balance.into(),
+            reward_rate: reward_rate.into(),
+            end_timestamp: self.reward_stream_end_timestamp.into(),
+            duration: self.reward_stream_duration.into(),
+        }
+    }
+}
+
+impl Farm {
+    /// Adds the deposit to the stream. The stream end moves towards now plus the stream duration
+    /// by the share of the deposit in the streamed balance, so small deposits can't delay the
+    /// release of the remaining balance.
+    pub(crate) fn add_to_reward_stream(&mut self, amount: Balance) {
+        self.advance_reward_stream();
+        let timestamp = env::block_timestamp();
+        let remaining_duration = self.reward_stream_end_timestamp.saturating_sub(timestamp);
+        self.reward_stream_balance += amount;
+        let duration = if self.reward_stream_duration == 0 || self.reward_stream_balance == 0 {
+            0
+        } else {
+            ((U256::from(self.reward_stream_balance - amount) * U256::from(remaining_duration)
+                + U256::from(amount) * U256::from(self.reward_stream_duration))
+                / U256::from(self.reward_stream_balance))
+            .as_u64()
+        };
+        self.reward_stream_end_timestamp = timestamp + duration;
+        // Distributes the deposit instantly when the stream duration is zero.
+        self.advance_reward_stream();
+    }
+
+    /// Moves the undistributed NEAR into the reward stream if there are enough cucumbers.
+    pub(crate) fn distribute_undistributed_near(&mut self) -> Balance {
+        let amount = self.undistributed_near;
+        if amount == 0 || self.total_weighted_balance() < NEAR_PER_CUCUMBER_DENOM {
+            return 0;
+        }
+        self.undistributed_near = 0;
+        self.add_to_reward_stream(amount);
+        emit_farm_event(
+            "reward_distribute_pending",
+            json!({
+                "amount": U128(amount),
+            }),
+        );
+        amount
+    }
+
+    /// Moves the NEAR released since the last update into `near_per_cucumber_numer`.
+    /// Should be called before `total_weighted_balance` changes.
+    pub(crate) fn advance_reward_stream(&mut self) {
+        let timestamp = env::block_timestamp();
+        if self.total_weighted_balance() < NEAR_PER_CUCUMBER_DENOM {
+            // Not enough cucumbers to distribute rewards, so the stream is paused.
+            if self.reward_stream_balance > 0 {
+                self.reward_stream_end_timestamp += timestamp - self.reward_stream_last_timestamp;
+            }
+        } else {
+            let released_balance = self.released_reward_stream_balance();
+            self.reward_stream_balance -= released_balance;
+            let (near_per_cucumber, distributed_balance) =
+                self.split_distribution(released_balance);
+            self.near_per_cucumber_numer += near_per_cucumber;
+            self.near_distribution_dust =
+                released_balance + self.near_distribution_dust - distributed_balance;
+            self.total_near_distributed += distributed_balance;
+        }
+        self.reward_stream_last_timestamp = timestamp;
+    }
+
+    /// Returns `near_per_cucumber_numer` including the NEAR released since the last update.
+    pub(crate) fn current_near_per_cucumber_numer(&self) -> Balance {
+        if self.total_weighted_balance() < NEAR_PER_CUCUMBER_DENOM {
+            self.near_per_cucumber_numer
+        } else {
+            self.near_per_cucumber_numer
+                + self
+                    .split_distribution(self.released_reward_stream_balance())
+                    .0
+        }
+    }
+
+    fn released_reward_stream_balance(&self) -> Balance {
+        let timestamp = env::block_timestamp();
+        if self.reward_stream_balance == 0
+            || self.total_weighted_balance() < NEAR_PER_CUCUMBER_DENOM
+        {
+            0
+        } else if timestamp >= self.reward_stream_end_timestamp {
+            self.reward_stream_balance
+        } else {
+            (U256::from(self.reward_stream_balance)
+                * U256::from(timestamp - self.reward_stream_last_timestamp)
+                / U256::from(self.reward_stream_end_timestamp - self.reward_stream_last_timestamp))
+            .as_u128()
+        }
+    }
+
+    /// Splits the released amount together with the dust of the previous distributions.
+    /// Returns the increase of `near_per_cucumber_numer` and the amount of NEAR it covers,
+    /// rounded up to yoctoNEAR. The rest stays as dust for the next distribution.
+    fn split_distribution(&self, amount: Balance) -> (Balance, Balance) {
+        let amount = U256::from(amount + self.near_distribution_dust);
+        let total_weighted_balance = U256::from(self.total_weighted_balance());
+        let denom = U256::from(NEAR_PER_CUCUMBER_DENOM);
+        let near_per_cucumber = amount * denom / total_weighted_balance;
+        let distributed_balance = (near_per_cucumber * total_weighted_balance + denom - 1) / denom;
+        (near_per_cucumber.as_u128(), distributed_balance.as_u128())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::test_utils::accounts;
+    const HOUR: Duration = 60 * 60 * 1_000_000_000;
+
+    fn stake(farm: &mut Farm, account_id: ValidAccountId, amount: Balance, timestamp: Timestamp) {
+        set_context(accounts(1), timestamp, 0);
+        farm.ft_on_transfer(account_id, amount.into(), "\"DepositAndStake\"".to_string());
+    }
+
+    fn deposit_near(farm: &mut Farm, amount: Balance, timestamp: Timestamp) {
+        set_context(accounts(5), timestamp, amount);
+        farm.take_my_near();
+    }
+
+    /// Checks the reconciliation identity and that the NEAR owed to the accounts is covered.
+    fn assert_reconciled(farm: &Farm) {
+        let reconciliation = farm.get_near_reconciliation();
+        assert_eq!(
+            reconciliation.total_near_received.0,
+            reconciliation.total_near_claimed.0
+                + reconciliation.outstanding_near.0
+                + reconciliation.near_distribution_dust.0
+                + reconciliation.undistributed_near.0
+                + reconciliation.reward_stream_balance.0
+        );
+        let owed_near: Balance = (2..5)
+            .filter_map(|index| farm.get_account(accounts(index)))
+            .map(|account| account.near_balance.0)
+            .sum();
+        assert!(owed_near <= reconciliation.outstanding_near.0);
+    }
+
+    #[test]
+    fn test_near_reconciliation() {
+        set_context(accounts(0), 0, 0);
+        let mut farm = Farm::new(accounts(1), accounts(0), None, None, None, None);
+        for index in 2..5 {
+            set_context(accounts(index), 0, ONE_NEAR);
+            farm.storage_deposit(None, None);
+        }
+
+        // Waits in the undistributed pool until there are enough cucumbers.
+        deposit_near(&mut farm, 7 * ONE_NEAR / 3, 1);
+        assert_reconciled(&farm);
+        stake(&mut farm, accounts(2), 3 * ONE_NEAR + 1, 2);
+        assert_reconciled(&farm);
+
+        deposit_near(&mut farm, ONE_NEAR / 7 + 13, 5 * HOUR + 3);
+        stake(&mut farm, accounts(3), 11 * ONE_NEAR / 9, 7 * HOUR + 11);
+        assert_reconciled(&farm);
+
+        set_context(accounts(2), 9 * HOUR + 17, 1);
+        farm.ft_transfer(accounts(4), (ONE_NEAR / 3).into(), None);
+        deposit_near(&mut farm, 5 * ONE_NEAR / 11 + 1, 10 * HOUR + 5);
+        assert_reconciled(&farm);
+
+        set_context(accounts(3), 13 * HOUR + 29, 0);
+        farm.claim_near();
+        assert_reconciled(&farm);
+
+        set_context(accounts(2), 17 * HOUR + 1, 1);
+        farm.unstake((ONE_NEAR + 7).into(), None);
+        deposit_near(&mut farm, 19, 20 * HOUR + 3);
+        assert_reconciled(&farm);
+
+        set_context(accounts(4), 23 * HOUR + 31, 1);
+        farm.storage_unregister(Some(true));
+        assert_reconciled(&farm);
+
+        // The stream has ended.
+        for index in 2..4 {
+            set_context(accounts(index), 60 * HOUR + 7, 0);
+            farm.claim_near();
+            assert_reconciled(&farm);
+        }
+        let reconciliation = farm.get_near_reconciliation();
+        assert_eq!(reconciliation.reward_stream_balance.0, 0);
+        assert_eq!(reconciliation.undistributed_near.0, 0);
+        assert!(reconciliation.total_near_claimed.0 > 0);
+    }
+
+    #[test]
+    fn test_small_deposit_keeps_stream_end() {
+        set_context(accounts(0), 0, 0);
+        let mut farm = Farm::new(accounts(1), accounts(0), None, None, None, None);
+        set_context(accounts(2), 0, ONE_NEAR);
+        farm.storage_deposit(None, None);
+        stake(&mut farm, accounts(2), ONE_NEAR, 0);
+        deposit_near(&mut farm, 10 * ONE_NEAR, 0);
+
+        deposit_near(&mut farm, 1, 12 * HOUR);
+        let reward_stream = farm.get_reward_stream();
+        assert_eq!(
+            reward_stream.end_timestamp.0,
+            DEFAULT_REWARD_STREAM_DURATION
+        );
+        assert_eq!(reward_stream.balance.0, 5 * ONE_NEAR + 1);
+
+        // An equal deposit moves the end halfway to the full duration.
+        deposit_near(&mut farm, 5 * ONE_NEAR + 1, 12 * HOUR);
+        let reward_stream = farm.get_reward_stream();
+        assert_eq!(reward_stream.end_timestamp.0, 30 * HOUR);
+    }
+}
@@ -0,0 +1,342 @@
+//! Reward pools of whitelisted NEP-141 tokens. The tokens are deposited with `ft_transfer_call`
+//! and the `DepositReward` payload, and distributed to the cucumber holders instantly, similar to
+//! the instant unstake penalties.
+//! Accounts keep a fixed size slot for every possible pool, so new pools don't change the storage
+//! of the accounts.
+use crate::*;
+use near_contract_standards::fungible_token::core_impl::ext_fungible_token;
+use near_sdk::{assert_one_yocto, Gas, PromiseOrValue, PromiseResult};
+
+/// The maximum number of reward tokens, including the disabled ones.
+pub const MAX_REWARD_TOKENS: usize = 10;
+
+const GAS_FOR_RESOLVE_CLAIM_TOKEN: Gas = 5_000_000_000_000;
+/// The gas for claiming the rewards of one token, including the transfer and the resolve.
+const GAS_FOR_CLAIM_TOKEN: Gas =
+    5_000_000_000_000 + GAS_FOR_FT_TRANSFER + GAS_FOR_RESOLVE_CLAIM_TOKEN;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct RewardPool {
+    pub token_id: AccountId,
+    /// Disabled pools don't accept deposits, but the earned tokens can still be claimed.
+    pub enabled: bool,
+    /// Accumulated token rewards per cucumber, similar to `near_per_cucumber_numer`.
+    pub reward_per_cucumber_numer: Balance,
+    pub total_received: Balance,
+    pub total_claimed: Balance,
+}
+
+/// The account's slot of a reward pool.
+#[derive(BorshDeserialize, BorshSerialize, Clone, Default)]
+pub struct TokenReward {
+    pub last_reward_per_cucumber_numer: Balance,
+    pub balance: Balance,
+    pub claimed: Balance,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HumanRewardPool {
+    pub token_id: AccountId,
+    pub enabled: bool,
+    pub total_received: U128,
+    pub total_claimed: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HumanTokenReward {
+    pub token_id: AccountId,
+    pub balance: U128,
+    pub claimed: U128,
+}
+
+#[ext_contract(ext_reward_tokens_self)]
+trait RewardTokensResolver {
+    fn resolve_claim_token(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) -> U128;
+}
+
+#[near_bindgen]
+impl Farm {
+    /// Whitelists the token as a reward token, or enables it again.
+    #[payable]
+    pub fn add_reward_token(&mut self, token_id: ValidAccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        let token_id: AccountId = token_id.into();
+        if let Some(pool_index) = self.reward_pool_index(&token_id) {
+            self.reward_pools[pool_index].enabled = true;
+        } else {
+            if self.reward_pools.len() >= MAX_REWARD_TOKENS {
+                env::panic(b"Too many reward tokens");
+            }
+            self.reward_pools.push(RewardPool {
+                token_id: token_id.clone(),
+                enabled: true,
+                reward_per_cucumber_numer: 0,
+                total_received: 0,
+                total_claimed: 0,
+            });
+        }
+        emit_farm_event(
+            "reward_token_add",
+            json!({
+                "token_id": token_id,
+            }),
+        );
+    }
+
+    /// Stops accepting the token as a reward. The earned tokens can still be claimed.
+    #[payable]
+    pub fn remove_reward_token(&mut self, token_id: ValidAccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        let pool_index = self
+            .reward_pool_index(token_id.as_ref())
+            .expect("The token is not a reward token");
+        self.reward_pools[pool_index].enabled = false;
+        emit_farm_event(
+            "reward_token_remove",
+            json!({
+                "token_id": token_id,
+            }),
+        );
+    }
+
+    /// Sends the predecessor's earned rewards of the token.
+    pub fn claim_token(&mut self, token_id: ValidAccountId) -> PromiseOrValue<U128> {
+        self.assert_claims_not_paused();
+        let pool_index = self
+            .reward_pool_index(token_id.as_ref())
+            .expect("The token is not a reward token");
+        let account_id = env::predecessor_account_id();
+        self.internal_claim_token(&account_id, pool_index)
+    }
+
+    /// Sends the predecessor's earned NEAR and the rewards of all the tokens.
+    /// Panics if the prepaid gas is not enough for all the claims, so none of them is skipped.
+    pub fn claim_all(&mut self) {
+        self.assert_claims_not_paused();
+        let account_id = env::predecessor_account_id();
+        let (_, account) = self.get_mut_account(&account_id);
+        let num_token_claims = account
+            .token_rewards
+            .iter()
+            .take(self.reward_pools.len())
+            .filter(|token_reward| token_reward.balance > 0)
+            .count();
+        let required_gas = claim_near_gas(&account) + GAS_FOR_CLAIM_TOKEN * num_token_claims as Gas;
+        if env::prepaid_gas() - env::used_gas() < required_gas {
+            env::panic(b"Not enough gas to claim all the rewards");
+        }
+        self.internal_claim_near(&account_id, None);
+        for pool_index in 0..self.reward_pools.len() {
+            self.internal_claim_token(&account_id, pool_index);
+        }
+    }
+
+    /// Returns the earned tokens back to the account if the transfer has failed.
+    /// Returns the amount of tokens that was actually claimed.
+    #[private]
+    pub fn resolve_claim_token(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        let amount: Balance = amount.into();
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => amount.into(),
+            PromiseResult::Failed => {
+                let pool_index = self.reward_pool_index(&token_id).unwrap();
+                // The tokens of the unregistered accounts stay on the farm.
+                if self.get_internal_account(&account_id).1.is_some() {
+                    let (account_id_hash, mut account) = self.get_mut_account(&account_id);
+                    let token_reward = &mut account.token_rewards[pool_index];
+                    token_reward.balance += amount;
+                    token_reward.claimed -= amount;
+                    self.save_account(&account_id_hash, &mut account);
+                    self.reward_pools[pool_index].total_claimed -= amount;
+                }
+                emit_farm_event(
+                    "token_claim_failed",
+                    json!({
+                        "account_id": account_id,
+                        "token_id": token_id,
+                        "amount": U128(amount),
+                    }),
+                );
+                0.into()
+            }
+        }
+    }
+
+    pub fn get_reward_pools(&self) -> Vec<HumanRewardPool> {
+        self.reward_pools
+            .iter()
+            .map(|pool| HumanRewardPool {
+                token_id: pool.token_id.clone(),
+                enabled: pool.enabled,
+                total_received: pool.total_received.into(),
+                total_claimed: pool.total_claimed.into(),
+            })
+            .collect()
+    }
+}
+
+impl Farm {
+    /// Distributes the token rewards received with `ft_on_transfer`. Returns the unused amount,
+    /// which is refunded when there are not enough cucumbers to distribute the rewards.
+    pub(crate) fn deposit_reward(
+        &mut self,
+        token_id: &AccountId,
+        sender_id: &AccountId,
+        amount: Balance,
+    ) -> Balance {
+        self.assert_reward_deposits_not_paused();
+        let pool_index = self
+            .reward_pool_index(token_id)
+            .filter(|&pool_index| self.reward_pools[pool_index].enabled)
+            .unwrap_or_else(|| env::panic(b"The token is not a whitelisted reward token"));
+        if self.total_weighted_balance() < NEAR_PER_CUCUMBER_DENOM {
+            return amount;
+        }
+        let reward_per_cucumber = (U256::from(amount) * U256::from(NEAR_PER_CUCUMBER_DENOM)
+            / U256::from(self.total_weighted_balance()))
+        .as_u128();
+        let pool = &mut self.reward_pools[pool_index];
+        pool.reward_per_cucumber_numer += reward_per_cucumber;
+        pool.total_received += amount;
+        emit_farm_event(
+            "reward_deposit",
+            json!({
+                "sender_id": sender_id,
+                "token_id": token_id,
+                "amount": U128(amount),
+            }),
+        );
+        0
+    }
+
+    /// Settles the token rewards of the account, part of `touch`.
+    pub(crate) fn touch_token_rewards(&self, account: &mut Account) {
+        let weighted_balance = account.weighted_balance();
+        for (pool, token_reward) in self
+            .reward_pools
+            .iter()
+            .zip(account.token_rewards.iter_mut())
+        {
+            let reward_per_cucumber_diff =
+                pool.reward_per_cucumber_numer - token_reward.last_reward_per_cucumber_numer;
+            let earned_balance = (U256::from(reward_per_cucumber_diff)
+                * U256::from(weighted_balance)
+                / U256::from(NEAR_PER_CUCUMBER_DENOM))
+            .as_u128();
+            token_reward.balance += earned_balance;
+            token_reward.last_reward_per_cucumber_numer = pool.reward_per_cucumber_numer;
+        }
+    }
+
+    /// Returns the earned rewards of every pool of the touched account.
+    pub(crate) fn human_token_rewards(&self, account: &Account) -> Vec<HumanTokenReward> {
+        self.reward_pools
+            .iter()
+            .zip(account.token_rewards.iter())
+            .map(|(pool, token_reward)| HumanTokenReward {
+                token_id: pool.token_id.clone(),
+                balance: token_reward.balance.into(),
+                claimed: token_reward.claimed.into(),
+            })
+            .collect()
+    }
+
+    pub(crate) fn has_token_rewards(&self, account: &Account) -> bool {
+        account
+            .token_rewards
+            .iter()
+            .any(|token_reward| token_reward.balance > 0)
+    }
+
+    fn reward_pool_index(&self, token_id: &AccountId) -> Option<usize> {
+        self.reward_pools
+            .iter()
+            .position(|pool| &pool.token_id == token_id)
+    }
+
+    fn internal_claim_token(
+        &mut self,
+        account_id: &AccountId,
+        pool_index: usize,
+    ) -> PromiseOrValue<U128> {
+        let (account_id_hash, mut account) = self.get_mut_account(account_id);
+        let token_reward = &mut account.token_rewards[pool_index];
+        let amount = token_reward.balance;
+        if amount == 0 {
+            return PromiseOrValue::Value(0.into());
+        }
+        token_reward.balance = 0;
+        token_reward.claimed += amount;
+        self.save_account(&account_id_hash, &mut account);
+        let pool = &mut self.reward_pools[pool_index];
+        pool.total_claimed += amount;
+        let token_id = pool.token_id.clone();
+        emit_farm_event(
+            "token_claim",
+            json!({
+                "account_id": account_id,
+                "token_id": token_id,
+                "amount": U128(amount),
+            }),
+        );
+
+        ext_fungible_token::ft_transfer(
+            account_id.clone(),
+            amount.into(),
+            None,
+            &token_id,
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_reward_tokens_self::resolve_claim_token(
+            account_id.clone(),
+            token_id,
+            amount.into(),
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_CLAIM_TOKEN,
+        ))
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    #[test]
+    #[should_panic(expected = "Not enough gas to claim all the rewards")]
+    fn test_claim_all_not_enough_gas() {
+        set_context(accounts(0), 0, 0);
+        let mut farm = Farm::new(accounts(1), accounts(0), None, None, None, None);
+        set_context(accounts(2), 0, ONE_NEAR);
+        farm.storage_deposit(None, None);
+
+        let context = VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(2))
+            .prepaid_gas(5_000_000_000_000)
+            .build();
+        testing_env!(context);
+        farm.claim_all();
+    }
+}
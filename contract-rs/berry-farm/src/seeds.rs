@@ -0,0 +1,466 @@
+//! Seeds are the berry tokens that can be staked for cucumbers. The bananas are always the first
+//! seed. Every seed mints cucumbers at its own ratio, and keeps the totals of the staked tokens and
+//! the cucumbers minted for them, so the cucumbers are unstaked back to the same token.
+//! Unstaking redeems the cucumbers at the seed's backing rate, `total_staked` per
+//! `total_cucumber_balance`, so changing the mint ratio can't make the seed insolvent.
+//! Accounts keep their cucumbers by the seed they were minted for, and can only redeem them for
+//! the same seed. Transfers move the cucumbers of the sender's seeds in the order of the seeds.
+use crate::*;
+use near_sdk::assert_one_yocto;
+
+/// The maximum number of seeds, including the bananas.
+pub const MAX_SEEDS: usize = 5;
+
+/// The index of the bananas in `Farm::seeds`.
+pub const BANANA_SEED_INDEX: usize = 0;
+
+pub const CUCUMBER_DECIMALS: u8 = 18;
+
+/// The maximum decimals of a seed token, so the mint ratio math fits into `U256`.
+pub const MAX_SEED_DECIMALS: u8 = 24;
+
+/// The mint ratio of 1 cucumber per 1 seed token, in basis points.
+pub const DEFAULT_MINT_RATIO_BPS: u32 = MAX_BASIS_POINTS as u32;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Seed {
+    pub token_id: AccountId,
+    pub decimals: u8,
+    /// Cucumbers minted per whole seed token, in basis points.
+    pub mint_ratio_bps: u32,
+    /// The maximum amount of the seed tokens that can be staked.
+    pub deposit_cap: Option<Balance>,
+    /// The staked seed tokens, excluding the unbonding ones.
+    pub total_staked: Balance,
+    /// The cucumbers minted for the staked seed tokens.
+    pub total_cucumber_balance: Balance,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HumanSeed {
+    pub token_id: AccountId,
+    pub decimals: u8,
+    pub mint_ratio_bps: u32,
+    pub deposit_cap: Option<U128>,
+    pub total_staked: U128,
+    pub total_cucumber_balance: U128,
+}
+
+impl Seed {
+    pub fn new(token_id: AccountId, decimals: u8, mint_ratio_bps: u32) -> Self {
+        Self {
+            token_id,
+            decimals,
+            mint_ratio_bps,
+            deposit_cap: None,
+            total_staked: 0,
+            total_cucumber_balance: 0,
+        }
+    }
+
+    /// Converts the seed tokens to cucumbers by the mint ratio.
+    fn cucumbers_for(&self, amount: Balance) -> Balance {
+        (U256::from(amount)
+            * U256::from(self.mint_ratio_bps)
+            * U256::exp10(CUCUMBER_DECIMALS as usize)
+            / (U256::exp10(self.decimals as usize) * U256::from(MAX_BASIS_POINTS)))
+        .as_u128()
+    }
+}
+
+#[near_bindgen]
+impl Farm {
+    /// Registers a new seed token. The farm should be registered with the token to withdraw it.
+    #[payable]
+    pub fn add_seed(
+        &mut self,
+        token_id: ValidAccountId,
+        decimals: u8,
+        mint_ratio_bps: u32,
+        deposit_cap: Option<U128>,
+    ) {
+        assert_one_yocto();
+        self.assert_owner();
+        if self.seed_index(token_id.as_ref()).is_some() {
+            env::panic(b"The seed already exists");
+        }
+        if self.seeds.len() >= MAX_SEEDS {
+            env::panic(b"Too many seeds");
+        }
+        if decimals > MAX_SEED_DECIMALS {
+            env::panic(b"Too many decimals of the seed token");
+        }
+        assert_valid_mint_ratio(mint_ratio_bps);
+        let mut seed = Seed::new(token_id.into(), decimals, mint_ratio_bps);
+        seed.deposit_cap = deposit_cap.map(|cap| cap.into());
+        emit_farm_event(
+            "seed_add",
+            json!({
+                "token_id": seed.token_id,
+                "decimals": seed.decimals,
+                "mint_ratio_bps": seed.mint_ratio_bps,
+                "deposit_cap": deposit_cap,
+            }),
+        );
+        self.seeds.push(seed);
+    }
+
+    /// Changes the mint ratio and the deposit cap of the seed for the future deposits.
+    /// A zero cap stops the deposits.
+    #[payable]
+    pub fn set_seed_config(
+        &mut self,
+        token_id: ValidAccountId,
+        mint_ratio_bps: u32,
+        deposit_cap: Option<U128>,
+    ) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert_valid_mint_ratio(mint_ratio_bps);
+        let seed_index = self
+            .seed_index(token_id.as_ref())
+            .expect("The token is not a seed");
+        let seed = &mut self.seeds[seed_index];
+        seed.mint_ratio_bps = mint_ratio_bps;
+        seed.deposit_cap = deposit_cap.map(|cap| cap.into());
+        emit_farm_event(
+            "seed_update",
+            json!({
+                "token_id": token_id,
+                "mint_ratio_bps": mint_ratio_bps,
+                "deposit_cap": deposit_cap,
+            }),
+        );
+    }
+
+    pub fn get_seeds(&self) -> Vec<HumanSeed> {
+        self.seeds
+            .iter()
+            .map(|seed| HumanSeed {
+                token_id: seed.token_id.clone(),
+                decimals: seed.decimals,
+                mint_ratio_bps: seed.mint_ratio_bps,
+                deposit_cap: seed.deposit_cap.map(|cap| cap.into()),
+                total_staked: seed.total_staked.into(),
+                total_cucumber_balance: seed.total_cucumber_balance.into(),
+            })
+            .collect()
+    }
+}
+
+impl Farm {
+    pub(crate) fn seed_index(&self, token_id: &AccountId) -> Option<usize> {
+        self.seeds
+            .iter()
+            .position(|seed| &seed.token_id == token_id)
+    }
+
+    /// Returns the index of the given seed, or the bananas by default.
+    pub(crate) fn seed_index_or_banana(&self, token_id: Option<ValidAccountId>) -> usize {
+        token_id
+            .map(|token_id| {
+                self.seed_index(token_id.as_ref())
+                    .expect("The token is not a seed")
+            })
+            .unwrap_or(BANANA_SEED_INDEX)
+    }
+
+    /// Returns the seed of the predecessor. Panics if the predecessor is not a seed token.
+    pub(crate) fn predecessor_seed_index(&self) -> usize {
+        self.seed_index(&env::predecessor_account_id())
+            .unwrap_or_else(|| {
+                env::panic(b"This farm can only receive seeds through a contract API")
+            })
+    }
+
+    /// Mints cucumbers for the staked seed tokens. Returns the minted amount.
+    pub(crate) fn stake_seed(
+        &mut self,
+        seed_index: usize,
+        account_id: &AccountId,
+        amount: Balance,
+    ) -> Balance {
+        let seed = &self.seeds[seed_index];
+        if let Some(deposit_cap) = seed.deposit_cap {
+            if seed.total_staked + amount > deposit_cap {
+                env::panic(b"The deposit exceeds the seed cap");
+            }
+        }
+        let cucumber_amount = seed.cucumbers_for(amount);
+        if cucumber_amount == 0 {
+            env::panic(b"The deposit is too small");
+        }
+        let (account_id_hash, mut account) = self.get_mut_account(account_id);
+        account.cucumber_balance += cucumber_amount;
+        account.seed_cucumber_balances[seed_index] += cucumber_amount;
+        self.save_account(&account_id_hash, &mut account);
+
+        let seed = &mut self.seeds[seed_index];
+        seed.total_staked += amount;
+        seed.total_cucumber_balance += cucumber_amount;
+        self.total_cucumber_balance += cucumber_amount;
+        emit_ft_mint(account_id, cucumber_amount, None);
+        self.distribute_undistributed_near();
+        cucumber_amount
+    }
+
+    /// Burns the account's cucumbers of the seed and removes them from the seed totals.
+    /// Returns the redeemed seed tokens. The caller updates the total cucumber balance.
+    pub(crate) fn redeem_seed(
+        &mut self,
+        account: &mut Account,
+        seed_index: usize,
+        cucumber_amount: Balance,
+    ) -> Balance {
+        if account.seed_cucumber_balances[seed_index] < cucumber_amount {
+            env::panic(b"Not enough cucumbers minted for the seed");
+        }
+        if account.unlocked_seed_balance(seed_index) < cucumber_amount {
+            env::panic(b"Not enough unlocked cucumber balance");
+        }
+        account.seed_cucumber_balances[seed_index] -= cucumber_amount;
+        account.cucumber_balance -= cucumber_amount;
+        let seed = &mut self.seeds[seed_index];
+        let amount = (U256::from(cucumber_amount) * U256::from(seed.total_staked)
+            / U256::from(seed.total_cucumber_balance))
+        .as_u128();
+        seed.total_staked -= amount;
+        seed.total_cucumber_balance -= cucumber_amount;
+        amount
+    }
+
+    /// Burns the cucumbers by the seed index that are not redeemed, e.g. of an unregistered
+    /// account. The staked seed tokens stay, raising the backing rate of the remaining cucumbers
+    /// of the seed.
+    pub(crate) fn burn_cucumbers(
+        &mut self,
+        owner_id: &AccountId,
+        seed_balances: &[Balance],
+        memo: Option<&str>,
+    ) {
+        self.advance_reward_stream();
+        let amount: Balance = seed_balances.iter().sum();
+        for (seed, balance) in self.seeds.iter_mut().zip(seed_balances) {
+            seed.total_cucumber_balance -= balance;
+        }
+        self.total_cucumber_balance -= amount;
+        if amount > 0 {
+            emit_ft_burn(owner_id, amount, memo);
+        }
+    }
+
+    /// Adds the seed tokens that have no owner, e.g. returned for an unregistered account, to the
+    /// seed backing, raising the backing rate of the seed's cucumbers.
+    pub(crate) fn add_seed_backing(
+        &mut self,
+        seed_index: usize,
+        account_id: &AccountId,
+        amount: Balance,
+    ) {
+        let seed = &mut self.seeds[seed_index];
+        seed.total_staked += amount;
+        emit_farm_event(
+            "seed_backing_deposit",
+            json!({
+                "account_id": account_id,
+                "token_id": seed.token_id,
+                "amount": U128(amount),
+            }),
+        );
+    }
+
+    /// Adds the redeemed seed tokens and the cucumbers burned for them back to the seed totals,
+    /// e.g. when the withdrawal has failed.
+    pub(crate) fn restore_seed(
+        &mut self,
+        seed_index: usize,
+        amount: Balance,
+        cucumber_amount: Balance,
+    ) {
+        let seed = &mut self.seeds[seed_index];
+        seed.total_staked += amount;
+        seed.total_cucumber_balance += cucumber_amount;
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HumanUnbonding {
+    pub token_id: AccountId,
+    /// The unstaked amount that is still in the unbonding period.
+    pub unbonding_balance: U128,
+    /// The unstaked amount that is ready to be withdrawn.
+    pub unbonded_balance: U128,
+}
+
+impl Farm {
+    pub(crate) fn human_unbonding(&self, account: &Account) -> Vec<HumanUnbonding> {
+        self.seeds
+            .iter()
+            .enumerate()
+            .map(|(seed_index, seed)| {
+                let unbonded_balance = account.unbonded_balance(seed_index);
+                HumanUnbonding {
+                    token_id: seed.token_id.clone(),
+                    unbonding_balance: (account.unbonding_balances[seed_index] - unbonded_balance)
+                        .into(),
+                    unbonded_balance: unbonded_balance.into(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Takes `amount` of cucumbers from the balances by the seed index, in the order of the seeds.
+/// Returns the taken cucumbers by the seed index.
+pub(crate) fn take_seed_balances(seed_balances: &mut [Balance], amount: Balance) -> Vec<Balance> {
+    let mut remaining_amount = amount;
+    let taken_balances = seed_balances
+        .iter_mut()
+        .map(|balance| {
+            let taken_amount = std::cmp::min(*balance, remaining_amount);
+            *balance -= taken_amount;
+            remaining_amount -= taken_amount;
+            taken_amount
+        })
+        .collect();
+    if remaining_amount > 0 {
+        env::panic(b"Not enough cucumbers of the seeds");
+    }
+    taken_balances
+}
+
+fn assert_valid_mint_ratio(mint_ratio_bps: u32) {
+    if mint_ratio_bps == 0 {
+        env::panic(b"The mint ratio should be positive");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::test_utils::accounts;
+
+    /// Creates the farm with a second seed, where the account 2 staked the second seed and sent
+    /// the cucumbers to the account 3, and the account 4 staked bananas.
+    fn setup_farm() -> Farm {
+        set_context(accounts(0), 0, 0);
+        let mut farm = Farm::new(accounts(1), accounts(0), None, None, None, None);
+        set_context(accounts(0), 0, 1);
+        farm.add_seed(accounts(5), CUCUMBER_DECIMALS, DEFAULT_MINT_RATIO_BPS, None);
+        for index in 2..5 {
+            set_context(accounts(index), 0, ONE_NEAR);
+            farm.storage_deposit(None, None);
+        }
+        set_context(accounts(5), 0, 0);
+        farm.ft_on_transfer(accounts(2), 10.into(), "\"DepositAndStake\"".to_string());
+        set_context(accounts(1), 0, 0);
+        farm.ft_on_transfer(accounts(4), 10.into(), "\"DepositAndStake\"".to_string());
+        set_context(accounts(2), 0, 1);
+        farm.ft_transfer(accounts(3), 6.into(), None);
+        farm
+    }
+
+    #[test]
+    fn test_unstake_transferred_seed() {
+        let mut farm = setup_farm();
+        set_context(accounts(3), 0, 1);
+        farm.unstake(6.into(), Some(accounts(5)));
+        let account = farm.get_account(accounts(3)).unwrap();
+        assert_eq!(account.cucumber_balance.0, 0);
+        assert_eq!(account.unbonding[1].unbonding_balance.0, 6);
+        assert_eq!(farm.seeds[1].total_staked, 4);
+        assert_eq!(farm.seeds[1].total_cucumber_balance, 4);
+        assert_eq!(farm.seeds[BANANA_SEED_INDEX].total_staked, 10);
+    }
+
+    #[test]
+    fn test_burn_raises_backing() {
+        let mut farm = setup_farm();
+        set_context(accounts(3), 0, 1);
+        farm.storage_unregister(Some(true));
+        assert_eq!(farm.seeds[1].total_cucumber_balance, 4);
+        assert_eq!(farm.seeds[1].total_staked, 10);
+        assert_eq!(farm.total_cucumber_balance, 14);
+
+        set_context(accounts(2), 0, 1);
+        farm.unstake(4.into(), Some(accounts(5)));
+        let account = farm.get_account(accounts(2)).unwrap();
+        assert_eq!(account.unbonding[1].unbonding_balance.0, 10);
+        assert_eq!(farm.seeds[1].total_staked, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough cucumbers minted for the seed")]
+    fn test_unstake_other_seed() {
+        let mut farm = setup_farm();
+        set_context(accounts(3), 0, 1);
+        farm.unstake(6.into(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough cucumbers minted for the seed")]
+    fn test_instant_unstake_other_seed() {
+        let mut farm = setup_farm();
+        set_context(accounts(2), 0, 1);
+        farm.instant_unstake(4.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many decimals of the seed token")]
+    fn test_add_seed_too_many_decimals() {
+        set_context(accounts(0), 0, 0);
+        let mut farm = Farm::new(accounts(1), accounts(0), None, None, None, None);
+        set_context(accounts(0), 0, 1);
+        farm.add_seed(
+            accounts(5),
+            MAX_SEED_DECIMALS + 1,
+            DEFAULT_MINT_RATIO_BPS,
+            None,
+        );
+    }
+
+    /// Locks 10 more bananas of the account 2, which holds 4 cucumbers of the second seed.
+    fn lock_bananas(farm: &mut Farm) {
+        set_context(accounts(1), 0, 0);
+        farm.ft_on_transfer(
+            accounts(2),
+            10.into(),
+            "{\"DepositAndLock\": {\"duration\": \"1000\"}}".to_string(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough unlocked cucumber balance")]
+    fn test_unstake_locked_seed() {
+        let mut farm = setup_farm();
+        lock_bananas(&mut farm);
+        set_context(accounts(2), 0, 1);
+        farm.unstake(4.into(), None);
+    }
+
+    #[test]
+    fn test_transfer_unlocked_seed() {
+        let mut farm = setup_farm();
+        lock_bananas(&mut farm);
+        set_context(accounts(2), 0, 1);
+        farm.ft_transfer(accounts(3), 4.into(), None);
+        let account = farm.get_account(accounts(2)).unwrap();
+        assert_eq!(account.cucumber_balance.0, 10);
+        assert_eq!(account.locked_balance.0, 10);
+        // The transferred cucumbers are redeemable for the second seed.
+        set_context(accounts(3), 0, 1);
+        farm.unstake(10.into(), Some(accounts(5)));
+        assert_eq!(farm.seeds[1].total_staked, 0);
+
+        // The bananas can be unstaked once the lock ends.
+        set_context(accounts(2), 1000, 1);
+        farm.unstake(10.into(), None);
+        assert_eq!(farm.seeds[BANANA_SEED_INDEX].total_staked, 10);
+    }
+}
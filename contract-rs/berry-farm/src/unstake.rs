@@ -0,0 +1,458 @@
+use crate::*;
+use near_contract_standards::fungible_token::core_impl::ext_fungible_token;
+use near_sdk::{assert_one_yocto, Gas, PromiseOrValue, PromiseResult};
+
+const GAS_FOR_RESOLVE_WITHDRAW_UNBONDED: Gas = 5_000_000_000_000;
+const GAS_FOR_RESOLVE_INSTANT_UNSTAKE: Gas = 5_000_000_000_000;
+const GAS_FOR_RESOLVE_CLAIM_BANANAS: Gas = 5_000_000_000_000;
+
+#[ext_contract(ext_unstake_self)]
+trait UnstakeResolver {
+    fn resolve_withdraw_unbonded(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) -> U128;
+    fn resolve_instant_unstake(
+        &mut self,
+        account_id: AccountId,
+        amount: U128,
+        penalty: U128,
+        cucumber_amount: U128,
+    ) -> U128;
+    fn resolve_claim_bananas(&mut self, account_id: AccountId, amount: U128) -> U128;
+}
+
+#[near_bindgen]
+impl Farm {
+    /// Burns `amount` of cucumbers minted for the seed from the predecessor and starts unbonding
+    /// the seed tokens redeemed for them, the bananas by default. The NEAR earned so far stays on
+    /// the account.
+    /// Unstaking again while unbonding restarts the unbonding period for the whole amount.
+    /// Returns the timestamp when the seed tokens can be withdrawn with `withdraw_unbonded`.
+    #[payable]
+    pub fn unstake(&mut self, amount: U128, seed_id: Option<ValidAccountId>) -> WrappedTimestamp {
+        assert_one_yocto();
+        let amount: Balance = amount.into();
+        if amount == 0 {
+            env::panic(b"Unstake amount should be positive");
+        }
+        let seed_index = self.seed_index_or_banana(seed_id);
+        let account_id = env::predecessor_account_id();
+        let (account_id_hash, mut account) = self.get_mut_account(&account_id);
+        let seed_amount = self.redeem_seed(&mut account, seed_index, amount);
+        account.unbonding_balances[seed_index] += seed_amount;
+        account.unbonding_release_timestamp = env::block_timestamp() + self.unbonding_period;
+        self.save_account(&account_id_hash, &mut account);
+
+        self.total_cucumber_balance -= amount;
+        emit_ft_burn(&account_id, amount, Some("unstake"));
+        emit_farm_event(
+            "unbond",
+            json!({
+                "account_id": account_id,
+                "token_id": self.seeds[seed_index].token_id,
+                "amount": U128(seed_amount),
+                "release_timestamp": WrappedTimestamp::from(account.unbonding_release_timestamp),
+            }),
+        );
+
+        account.unbonding_release_timestamp.into()
+    }
+
+    /// Sends the seed tokens of the predecessor that finished unbonding, the bananas by default.
+    #[payable]
+    pub fn withdraw_unbonded(&mut self, seed_id: Option<ValidAccountId>) -> Promise {
+        assert_one_yocto();
+        self.assert_claims_not_paused();
+        let seed_index = self.seed_index_or_banana(seed_id);
+        let account_id = env::predecessor_account_id();
+        let (account_id_hash, mut account) = self.get_mut_account(&account_id);
+        let amount = account.unbonded_balance(seed_index);
+        if amount == 0 {
+            env::panic(b"Nothing to withdraw");
+        }
+        account.unbonding_balances[seed_index] -= amount;
+        self.save_account(&account_id_hash, &mut account);
+        let token_id = self.seeds[seed_index].token_id.clone();
+        emit_farm_event(
+            "unbond_withdraw",
+            json!({
+                "account_id": account_id,
+                "token_id": token_id,
+                "amount": U128(amount),
+            }),
+        );
+
+        self.transfer_seed(seed_index, &account_id, amount).then(
+            ext_unstake_self::resolve_withdraw_unbonded(
+                account_id,
+                token_id,
+                amount.into(),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_RESOLVE_WITHDRAW_UNBONDED,
+            ),
+        )
+    }
+
+    /// Returns the seed tokens back to the unbonding balance if the transfer has failed. If the
+    /// account has unregistered in the meantime, the seed tokens go to the seed backing.
+    /// Returns the amount of seed tokens that was actually withdrawn.
+    #[private]
+    pub fn resolve_withdraw_unbonded(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        let amount: Balance = amount.into();
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => amount.into(),
+            PromiseResult::Failed => {
+                let seed_index = self.seed_index(&token_id).unwrap();
+                if self.get_internal_account(&account_id).1.is_some() {
+                    let (account_id_hash, mut account) = self.get_mut_account(&account_id);
+                    account.unbonding_balances[seed_index] += amount;
+                    self.save_account(&account_id_hash, &mut account);
+                } else {
+                    self.add_seed_backing(seed_index, &account_id, amount);
+                }
+                emit_farm_event(
+                    "unbond_withdraw_failed",
+                    json!({
+                        "account_id": account_id,
+                        "token_id": token_id,
+                        "amount": U128(amount),
+                    }),
+                );
+                0.into()
+            }
+        }
+    }
+
+    /// Burns `amount` of cucumbers minted for bananas from the predecessor and immediately sends
+    /// back the bananas redeemed for them minus the instant unstake penalty. Once the transfer
+    /// succeeds, the penalty is distributed to the remaining cucumber holders and can be claimed
+    /// with `claim_bananas`.
+    /// The penalty is waived when there are not enough cucumbers left to distribute it.
+    /// Only the banana seed can be unstaked instantly.
+    #[payable]
+    pub fn instant_unstake(&mut self, amount: U128) -> Promise {
+        assert_one_yocto();
+        let amount: Balance = amount.into();
+        if amount == 0 {
+            env::panic(b"Unstake amount should be positive");
+        }
+        let account_id = env::predecessor_account_id();
+        let (account_id_hash, mut account) = self.get_mut_account(&account_id);
+        let cucumber_amount = amount;
+        let amount = self.redeem_seed(&mut account, BANANA_SEED_INDEX, cucumber_amount);
+        self.save_account(&account_id_hash, &mut account);
+
+        self.total_cucumber_balance -= cucumber_amount;
+        emit_ft_burn(&account_id, cucumber_amount, Some("instant unstake"));
+
+        let penalty = if self.total_weighted_balance() >= NEAR_PER_CUCUMBER_DENOM {
+            (U256::from(amount) * U256::from(self.instant_unstake_penalty_bps)
+                / U256::from(MAX_BASIS_POINTS))
+            .as_u128()
+        } else {
+            0
+        };
+        let amount = amount - penalty;
+
+        self.transfer_seed(BANANA_SEED_INDEX, &account_id, amount)
+            .then(ext_unstake_self::resolve_instant_unstake(
+                account_id,
+                amount.into(),
+                penalty.into(),
+                cucumber_amount.into(),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_RESOLVE_INSTANT_UNSTAKE,
+            ))
+    }
+
+    /// Distributes the penalty if the transfer has succeeded. Otherwise stakes the bananas back
+    /// together with the penalty, by minting the burned cucumbers again. If the account has
+    /// unregistered in the meantime, the bananas go to the banana backing.
+    /// Returns the amount of bananas that was actually unstaked.
+    #[private]
+    pub fn resolve_instant_unstake(
+        &mut self,
+        account_id: AccountId,
+        amount: U128,
+        penalty: U128,
+        cucumber_amount: U128,
+    ) -> U128 {
+        let amount: Balance = amount.into();
+        let penalty: Balance = penalty.into();
+        let cucumber_amount: Balance = cucumber_amount.into();
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => {
+                if penalty > 0 {
+                    self.distribute_penalty(&account_id, penalty);
+                }
+                amount.into()
+            }
+            PromiseResult::Failed => {
+                if self.get_internal_account(&account_id).1.is_none() {
+                    self.add_seed_backing(BANANA_SEED_INDEX, &account_id, amount + penalty);
+                    return 0.into();
+                }
+                let (account_id_hash, mut account) = self.get_mut_account(&account_id);
+                self.restore_seed(BANANA_SEED_INDEX, amount + penalty, cucumber_amount);
+                account.cucumber_balance += cucumber_amount;
+                account.seed_cucumber_balances[BANANA_SEED_INDEX] += cucumber_amount;
+                self.save_account(&account_id_hash, &mut account);
+
+                self.total_cucumber_balance += cucumber_amount;
+                emit_ft_mint(&account_id, cucumber_amount, Some("refund"));
+                0.into()
+            }
+        }
+    }
+
+    /// Sends the bananas earned from instant unstake penalties to the predecessor.
+    pub fn claim_bananas(&mut self) -> PromiseOrValue<U128> {
+        self.assert_claims_not_paused();
+        let account_id = env::predecessor_account_id();
+        let (account_id_hash, mut account) = self.get_mut_account(&account_id);
+        let amount = account.banana_balance;
+        if amount == 0 {
+            return PromiseOrValue::Value(0.into());
+        }
+        account.banana_balance = 0;
+        account.banana_claimed += amount;
+        self.save_account(&account_id_hash, &mut account);
+        self.total_banana_claimed += amount;
+        emit_farm_event(
+            "banana_claim",
+            json!({
+                "account_id": account_id,
+                "amount": U128(amount),
+            }),
+        );
+
+        self.transfer_seed(BANANA_SEED_INDEX, &account_id, amount)
+            .then(ext_unstake_self::resolve_claim_bananas(
+                account_id,
+                amount.into(),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_RESOLVE_CLAIM_BANANAS,
+            ))
+            .into()
+    }
+
+    /// Returns the bananas back to the account if the transfer has failed. If the account has
+    /// unregistered in the meantime, the bananas go to the banana backing.
+    /// Returns the amount of bananas that was actually claimed.
+    #[private]
+    pub fn resolve_claim_bananas(&mut self, account_id: AccountId, amount: U128) -> U128 {
+        let amount: Balance = amount.into();
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => amount.into(),
+            PromiseResult::Failed => {
+                if self.get_internal_account(&account_id).1.is_some() {
+                    let (account_id_hash, mut account) = self.get_mut_account(&account_id);
+                    account.banana_balance += amount;
+                    account.banana_claimed -= amount;
+                    self.save_account(&account_id_hash, &mut account);
+                } else {
+                    self.add_seed_backing(BANANA_SEED_INDEX, &account_id, amount);
+                }
+                self.total_banana_claimed -= amount;
+                emit_farm_event(
+                    "banana_claim_failed",
+                    json!({
+                        "account_id": account_id,
+                        "amount": U128(amount),
+                    }),
+                );
+                0.into()
+            }
+        }
+    }
+}
+
+impl Farm {
+    /// Distributes the instant unstake penalty to the cucumber holders. If there are not enough
+    /// cucumbers left, the penalty is added to the banana backing instead.
+    fn distribute_penalty(&mut self, account_id: &AccountId, penalty: Balance) {
+        if self.total_weighted_balance() < NEAR_PER_CUCUMBER_DENOM {
+            self.add_seed_backing(BANANA_SEED_INDEX, account_id, penalty);
+            return;
+        }
+        let banana_per_cucumber = (U256::from(penalty) * U256::from(NEAR_PER_CUCUMBER_DENOM)
+            / U256::from(self.total_weighted_balance()))
+        .as_u128();
+        self.banana_per_cucumber_numer += banana_per_cucumber;
+        self.total_banana_received += penalty;
+        emit_farm_event(
+            "penalty_deposit",
+            json!({
+                "account_id": account_id,
+                "amount": U128(penalty),
+            }),
+        );
+    }
+
+    fn transfer_seed(
+        &self,
+        seed_index: usize,
+        receiver_id: &AccountId,
+        amount: Balance,
+    ) -> Promise {
+        ext_fungible_token::ft_transfer(
+            receiver_id.clone(),
+            amount.into(),
+            None,
+            &self.seeds[seed_index].token_id,
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::test_utils::accounts;
+
+    /// Creates the farm where the account 2 staked 10 bananas.
+    fn setup_farm() -> Farm {
+        set_context(accounts(0), 0, 0);
+        let mut farm = Farm::new(accounts(1), accounts(0), None, None, None, None);
+        set_context(accounts(2), 0, ONE_NEAR);
+        farm.storage_deposit(None, None);
+        set_context(accounts(1), 0, 0);
+        farm.ft_on_transfer(accounts(2), 10.into(), "\"DepositAndStake\"".to_string());
+        farm
+    }
+
+    #[test]
+    fn test_unstake_while_staking_paused() {
+        let mut farm = setup_farm();
+        set_context(accounts(0), 0, 1);
+        farm.set_pause_status(Some(true), None, None, None);
+
+        set_context(accounts(2), 0, 1);
+        farm.unstake(4.into(), None);
+        farm.instant_unstake(6.into());
+        let account = farm.get_account(accounts(2)).unwrap();
+        assert_eq!(account.cucumber_balance.0, 0);
+        assert_eq!(account.unbonding[BANANA_SEED_INDEX].unbonding_balance.0, 4);
+    }
+
+    #[test]
+    fn test_failed_instant_unstake_after_ratio_change() {
+        let mut farm = setup_farm();
+        set_context(accounts(0), 0, 1);
+        farm.set_seed_config(accounts(1), DEFAULT_MINT_RATIO_BPS / 2, None);
+
+        set_context(accounts(2), 0, 1);
+        farm.instant_unstake(10.into());
+        set_callback_context(PromiseResult::Failed);
+        assert_eq!(
+            farm.resolve_instant_unstake(accounts(2).into(), 10.into(), 0.into(), 10.into())
+                .0,
+            0
+        );
+        let account = farm.get_account(accounts(2)).unwrap();
+        assert_eq!(account.cucumber_balance.0, 10);
+        assert_eq!(farm.seeds[BANANA_SEED_INDEX].total_staked, 10);
+        assert_eq!(farm.seeds[BANANA_SEED_INDEX].total_cucumber_balance, 10);
+        assert_eq!(farm.total_cucumber_balance, 10);
+    }
+
+    #[test]
+    fn test_failed_instant_unstake_keeps_penalty() {
+        let mut farm = setup_farm();
+        set_context(accounts(3), 0, ONE_NEAR);
+        farm.storage_deposit(None, None);
+        set_context(accounts(1), 0, 0);
+        farm.ft_on_transfer(
+            accounts(3),
+            NEAR_PER_CUCUMBER_DENOM.into(),
+            "\"DepositAndStake\"".to_string(),
+        );
+
+        set_context(accounts(2), 0, 1);
+        farm.instant_unstake(10.into());
+        set_callback_context(PromiseResult::Failed);
+        farm.resolve_instant_unstake(accounts(2).into(), 9.into(), 1.into(), 10.into());
+        let account = farm.get_account(accounts(2)).unwrap();
+        assert_eq!(account.cucumber_balance.0, 10);
+        let account = farm.get_account(accounts(3)).unwrap();
+        assert_eq!(account.banana_balance.0, 0);
+        assert_eq!(
+            farm.seeds[BANANA_SEED_INDEX].total_staked,
+            NEAR_PER_CUCUMBER_DENOM + 10
+        );
+    }
+
+    #[test]
+    fn test_failed_withdraw_unbonded_of_unregistered_account() {
+        let mut farm = setup_farm();
+        set_context(accounts(2), 0, 1);
+        farm.unstake(4.into(), None);
+        set_context(accounts(2), DEFAULT_UNBONDING_PERIOD, 1);
+        farm.withdraw_unbonded(None);
+        farm.storage_unregister(Some(true));
+
+        set_callback_context(PromiseResult::Failed);
+        let amount =
+            farm.resolve_withdraw_unbonded(accounts(2).into(), accounts(1).into(), 4.into());
+        assert_eq!(amount.0, 0);
+        // The 6 bananas of the burned cucumbers and the 4 returned ones.
+        assert_eq!(farm.seeds[BANANA_SEED_INDEX].total_staked, 10);
+        assert_eq!(farm.seeds[BANANA_SEED_INDEX].total_cucumber_balance, 0);
+    }
+
+    #[test]
+    fn test_failed_instant_unstake_of_unregistered_account() {
+        let mut farm = setup_farm();
+        set_context(accounts(2), 0, 1);
+        farm.instant_unstake(4.into());
+        farm.storage_unregister(Some(true));
+
+        set_callback_context(PromiseResult::Failed);
+        farm.resolve_instant_unstake(accounts(2).into(), 4.into(), 0.into(), 4.into());
+        assert_eq!(farm.seeds[BANANA_SEED_INDEX].total_staked, 10);
+        assert_eq!(farm.total_cucumber_balance, 0);
+    }
+
+    #[test]
+    fn test_failed_claim_bananas_of_unregistered_account() {
+        let mut farm = setup_farm();
+        set_context(accounts(2), 0, 1);
+        farm.instant_unstake(4.into());
+        set_callback_context(PromiseResult::Successful(vec![]));
+        farm.resolve_instant_unstake(accounts(2).into(), 4.into(), 0.into(), 4.into());
+        farm.banana_per_cucumber_numer += NEAR_PER_CUCUMBER_DENOM / 2;
+        farm.total_banana_received += 3;
+
+        set_context(accounts(2), 0, 1);
+        farm.claim_bananas();
+        farm.storage_unregister(Some(true));
+        assert_eq!(farm.total_banana_claimed, 3);
+
+        set_callback_context(PromiseResult::Failed);
+        assert_eq!(
+            farm.resolve_claim_bananas(accounts(2).into(), 3.into()).0,
+            0
+        );
+        assert_eq!(farm.total_banana_claimed, 0);
+        // The 6 bananas of the burned cucumbers and the 3 returned ones.
+        assert_eq!(farm.seeds[BANANA_SEED_INDEX].total_staked, 9);
+    }
+}
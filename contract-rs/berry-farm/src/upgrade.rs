@@ -0,0 +1,201 @@
+use crate::*;
+use near_sdk::json_types::Base58CryptoHash;
+use near_sdk::{assert_one_yocto, CryptoHash, Gas};
+use std::convert::TryInto;
+
+const STAGED_CODE_KEY: &[u8] = b"STAGED_CODE";
+
+/// Gas needed for `deploy_upgrade` to read the code and schedule the deployment.
+const GAS_FOR_DEPLOY_UPGRADE: Gas = 30_000_000_000_000;
+
+/// 24 hours in nanoseconds.
+pub const DEFAULT_UPGRADE_DELAY: Duration = 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct StagedUpgrade {
+    /// The sha256 hash of the staged code.
+    pub code_hash: CryptoHash,
+    /// The timestamp after which the staged code can be deployed.
+    pub activation_timestamp: Timestamp,
+    /// The owner who staged the code and paid for its storage.
+    pub owner_id: AccountId,
+    /// The deposit attached to cover the storage of the staged code, refunded once the code is
+    /// cancelled, replaced or deployed.
+    pub storage_deposit: Balance,
+}
+
+impl StagedUpgrade {
+    pub(crate) fn refund_storage_deposit(&self) {
+        if self.storage_deposit > 0 {
+            Promise::new(self.owner_id.clone()).transfer(self.storage_deposit);
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HumanStagedUpgrade {
+    pub code_hash: Base58CryptoHash,
+    pub activation_timestamp: WrappedTimestamp,
+}
+
+#[near_bindgen]
+impl Farm {
+    /// Stages the new contract code. The code is passed as the raw input of the method.
+    /// The attached deposit should cover the storage of the code, it's refunded once the code is
+    /// cancelled, replaced or deployed.
+    /// It can be deployed with `deploy_upgrade` after the upgrade delay.
+    /// Staging a new code replaces the previously staged one and restarts the delay.
+    #[payable]
+    pub fn stage_upgrade(&mut self) {
+        self.assert_owner();
+        let code = env::input().expect("The code should be passed as the input");
+        let code_hash: CryptoHash = env::sha256(&code).try_into().unwrap();
+        if let Some(staged_upgrade) = self.staged_upgrade.take() {
+            env::storage_remove(STAGED_CODE_KEY);
+            staged_upgrade.refund_storage_deposit();
+        }
+        let initial_storage_usage = env::storage_usage();
+        env::storage_write(STAGED_CODE_KEY, &code);
+        let storage_cost =
+            (env::storage_usage() - initial_storage_usage) as Balance * env::storage_byte_cost();
+        let storage_deposit = env::attached_deposit();
+        if storage_deposit < storage_cost {
+            env::panic(b"The attached deposit doesn't cover the storage of the staged code");
+        }
+        let staged_upgrade = StagedUpgrade {
+            code_hash,
+            activation_timestamp: env::block_timestamp() + self.upgrade_delay,
+            owner_id: env::predecessor_account_id(),
+            storage_deposit,
+        };
+        emit_farm_event(
+            "upgrade_stage",
+            json!({
+                "code_hash": Base58CryptoHash::from(code_hash),
+                "activation_timestamp": WrappedTimestamp::from(staged_upgrade.activation_timestamp),
+                "storage_deposit": U128(storage_deposit),
+            }),
+        );
+        self.staged_upgrade = Some(staged_upgrade);
+    }
+
+    /// Deploys the staged code and calls `migrate` on it. The staged code is removed and its
+    /// storage deposit is refunded by `migrate`, so it can be deployed again if the migration
+    /// fails.
+    #[payable]
+    pub fn deploy_upgrade(&mut self) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
+        let staged_upgrade = self.staged_upgrade.as_ref().expect("No upgrade is staged");
+        if env::block_timestamp() < staged_upgrade.activation_timestamp {
+            env::panic(b"The upgrade delay hasn't passed yet");
+        }
+        let code = env::storage_read(STAGED_CODE_KEY).expect("The staged code is missing");
+        emit_farm_event(
+            "upgrade_deploy",
+            json!({
+                "code_hash": Base58CryptoHash::from(staged_upgrade.code_hash),
+            }),
+        );
+
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call(
+                b"migrate".to_vec(),
+                vec![],
+                NO_DEPOSIT,
+                env::prepaid_gas() - GAS_FOR_DEPLOY_UPGRADE,
+            )
+    }
+
+    /// Removes the staged code and refunds its storage deposit.
+    #[payable]
+    pub fn cancel_upgrade(&mut self) {
+        assert_one_yocto();
+        self.assert_owner();
+        let staged_upgrade = self.staged_upgrade.take().expect("No upgrade is staged");
+        env::storage_remove(STAGED_CODE_KEY);
+        staged_upgrade.refund_storage_deposit();
+        emit_farm_event(
+            "upgrade_cancel",
+            json!({
+                "code_hash": Base58CryptoHash::from(staged_upgrade.code_hash),
+            }),
+        );
+    }
+
+    /// Increases the upgrade delay. It can't be decreased, otherwise the owner could skip it.
+    /// It doesn't affect the already staged upgrade.
+    #[payable]
+    pub fn set_upgrade_delay(&mut self, upgrade_delay: WrappedDuration) {
+        assert_one_yocto();
+        self.assert_owner();
+        let upgrade_delay: Duration = upgrade_delay.into();
+        if upgrade_delay < self.upgrade_delay {
+            env::panic(b"The upgrade delay can't be decreased");
+        }
+        self.upgrade_delay = upgrade_delay;
+    }
+
+    pub fn get_upgrade_delay(&self) -> WrappedDuration {
+        self.upgrade_delay.into()
+    }
+
+    pub fn get_staged_upgrade(&self) -> Option<HumanStagedUpgrade> {
+        self.staged_upgrade
+            .as_ref()
+            .map(|staged_upgrade| HumanStagedUpgrade {
+                code_hash: staged_upgrade.code_hash.into(),
+                activation_timestamp: staged_upgrade.activation_timestamp.into(),
+            })
+    }
+}
+
+/// Removes the deployed code, called by `migrate` once the upgrade has succeeded.
+pub(crate) fn remove_staged_code() {
+    env::storage_remove(STAGED_CODE_KEY);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    fn stage(farm: &mut Farm, code: &[u8], deposit: Balance) {
+        let mut context = VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(deposit)
+            .build();
+        context.input = code.to_vec();
+        testing_env!(context);
+        farm.stage_upgrade();
+    }
+
+    #[test]
+    #[should_panic(expected = "The attached deposit doesn't cover the storage of the staged code")]
+    fn test_stage_upgrade_without_storage_deposit() {
+        set_context(accounts(0), 0, 0);
+        let mut farm = Farm::new(accounts(1), accounts(0), None, None, None, None);
+        stage(&mut farm, &[0; 1000], 1);
+    }
+
+    #[test]
+    fn test_cancel_upgrade_refund() {
+        set_context(accounts(0), 0, 0);
+        let mut farm = Farm::new(accounts(1), accounts(0), None, None, None, None);
+        stage(&mut farm, &[0; 1000], ONE_NEAR);
+        assert_eq!(
+            farm.staged_upgrade.as_ref().unwrap().storage_deposit,
+            ONE_NEAR
+        );
+        set_context(accounts(0), 0, 1);
+        farm.cancel_upgrade();
+        assert!(farm.staged_upgrade.is_none());
+        // The refund of the storage deposit.
+        assert_eq!(near_sdk::test_utils::get_created_receipts().len(), 1);
+    }
+}
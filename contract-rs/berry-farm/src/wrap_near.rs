@@ -0,0 +1,389 @@
+//! Claiming NEAR as wrapped NEAR. The farm deposits the claimed NEAR into the configured wNEAR
+//! contract and transfers the minted tokens to the claimer.
+use crate::*;
+use near_sdk::{assert_one_yocto, Gas, PromiseOrValue, PromiseResult};
+
+const GAS_FOR_NEAR_DEPOSIT: Gas = 10_000_000_000_000;
+const GAS_FOR_NEAR_WITHDRAW: Gas = 10_000_000_000_000;
+const GAS_FOR_ON_NEAR_UNWRAPPED: Gas = 5_000_000_000_000;
+const GAS_FOR_RESOLVE_CLAIM_WRAPPED: Gas =
+    5_000_000_000_000 + GAS_FOR_NEAR_WITHDRAW + GAS_FOR_ON_NEAR_UNWRAPPED;
+const GAS_FOR_ON_NEAR_WRAPPED: Gas =
+    5_000_000_000_000 + GAS_FOR_FT_TRANSFER + GAS_FOR_RESOLVE_CLAIM_WRAPPED;
+
+#[ext_contract(ext_wrap_near)]
+pub trait WrapNear {
+    fn near_deposit(&mut self);
+    fn near_withdraw(&mut self, amount: U128);
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+}
+
+#[ext_contract(ext_wrap_near_self)]
+trait WrapNearResolver {
+    fn on_near_wrapped(
+        &mut self,
+        account_id: AccountId,
+        token_contract: AccountId,
+        amount: U128,
+        wrapped_amount: U128,
+    ) -> PromiseOrValue<U128>;
+    fn resolve_claim_wrapped(
+        &mut self,
+        account_id: AccountId,
+        token_contract: AccountId,
+        amount: U128,
+        wrapped_amount: U128,
+    ) -> U128;
+    fn on_near_unwrapped(&mut self, account_id: AccountId, token_contract: AccountId, amount: U128);
+}
+
+#[near_bindgen]
+impl Farm {
+    /// Sets the wNEAR contract used by `claim_near_wrapped`. The farm should be registered with
+    /// its storage. Can't be changed while the farm holds wNEAR for the accounts.
+    #[payable]
+    pub fn set_wrap_near_account_id(&mut self, wrap_near_account_id: Option<ValidAccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        if self.total_wrapped_near_owed > 0 {
+            env::panic(b"The farm holds wNEAR for the accounts");
+        }
+        self.wrap_near_account_id = wrap_near_account_id.map(|id| id.into());
+    }
+
+    pub fn get_wrap_near_account_id(&self) -> Option<AccountId> {
+        self.wrap_near_account_id.clone()
+    }
+
+    /// Claims the NEAR earned by the predecessor as wNEAR of `token_contract`, which should be
+    /// the configured wNEAR contract. The predecessor should be registered with that contract.
+    /// The wNEAR held for the predecessor after a failed claim is sent along.
+    /// The claim is rolled back if either the deposit or the transfer fails.
+    #[payable]
+    pub fn claim_near_wrapped(&mut self, token_contract: ValidAccountId) -> PromiseOrValue<U128> {
+        assert_one_yocto();
+        self.assert_claims_not_paused();
+        if self.wrap_near_account_id.as_ref() != Some(token_contract.as_ref()) {
+            env::panic(b"The token contract is not the configured wNEAR contract");
+        }
+        let account_id = env::predecessor_account_id();
+        let (account_id_hash, mut account) = self.get_mut_account(&account_id);
+        let wrapped_amount = account.wrapped_near_balance;
+        if wrapped_amount > 0 {
+            account.wrapped_near_balance = 0;
+            self.total_wrapped_near_owed -= wrapped_amount;
+        }
+        // Saves the account if there is NEAR to claim.
+        let amount = self.take_claim(&account_id_hash, &mut account);
+        if amount == 0 {
+            if wrapped_amount == 0 {
+                return PromiseOrValue::Value(0.into());
+            }
+            self.save_account(&account_id_hash, &mut account);
+            return self
+                .transfer_wrapped_near(account_id, token_contract.into(), 0, wrapped_amount)
+                .into();
+        }
+
+        ext_wrap_near::near_deposit(token_contract.as_ref(), amount, GAS_FOR_NEAR_DEPOSIT)
+            .then(ext_wrap_near_self::on_near_wrapped(
+                account_id,
+                token_contract.into(),
+                amount.into(),
+                wrapped_amount.into(),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_ON_NEAR_WRAPPED,
+            ))
+            .into()
+    }
+
+    /// Transfers the wrapped NEAR to the claimer. Rolls back the claim if the deposit has failed,
+    /// the deposited NEAR is refunded to the farm in this case.
+    #[private]
+    pub fn on_near_wrapped(
+        &mut self,
+        account_id: AccountId,
+        token_contract: AccountId,
+        amount: U128,
+        wrapped_amount: U128,
+    ) -> PromiseOrValue<U128> {
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => self
+                .transfer_wrapped_near(account_id, token_contract, amount.0, wrapped_amount.0)
+                .into(),
+            PromiseResult::Failed => {
+                self.fail_claim_wrapped(&account_id, &token_contract, amount.into());
+                if wrapped_amount.0 > 0 {
+                    self.owe_wrapped_near(&account_id, &token_contract, wrapped_amount.into());
+                }
+                PromiseOrValue::Value(0.into())
+            }
+        }
+    }
+
+    /// Unwraps the wNEAR back if the transfer has failed, the claim is rolled back once it's
+    /// unwrapped. Returns the amount of NEAR that was actually claimed.
+    #[private]
+    pub fn resolve_claim_wrapped(
+        &mut self,
+        account_id: AccountId,
+        token_contract: AccountId,
+        amount: U128,
+        wrapped_amount: U128,
+    ) -> U128 {
+        let total_amount = amount.0 + wrapped_amount.0;
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => {
+                emit_farm_event(
+                    "near_claim",
+                    json!({
+                        "account_id": account_id,
+                        "receiver_id": account_id,
+                        "amount": U128(total_amount),
+                        "token_id": token_contract,
+                    }),
+                );
+                total_amount.into()
+            }
+            PromiseResult::Failed => {
+                ext_wrap_near::near_withdraw(
+                    total_amount.into(),
+                    &token_contract,
+                    ONE_YOCTO,
+                    GAS_FOR_NEAR_WITHDRAW,
+                )
+                .then(ext_wrap_near_self::on_near_unwrapped(
+                    account_id,
+                    token_contract,
+                    total_amount.into(),
+                    &env::current_account_id(),
+                    NO_DEPOSIT,
+                    GAS_FOR_ON_NEAR_UNWRAPPED,
+                ));
+                0.into()
+            }
+        }
+    }
+
+    /// Rolls back the claim once the wNEAR of the failed transfer is unwrapped. Otherwise the farm
+    /// keeps holding the wNEAR for the account.
+    #[private]
+    pub fn on_near_unwrapped(
+        &mut self,
+        account_id: AccountId,
+        token_contract: AccountId,
+        amount: U128,
+    ) {
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => {
+                self.fail_claim_wrapped(&account_id, &token_contract, amount.into())
+            }
+            PromiseResult::Failed => {
+                self.owe_wrapped_near(&account_id, &token_contract, amount.into())
+            }
+        }
+    }
+}
+
+impl Farm {
+    /// Transfers the wrapped claim of `amount` and the held wNEAR of `wrapped_amount` to the
+    /// claimer.
+    fn transfer_wrapped_near(
+        &mut self,
+        account_id: AccountId,
+        token_contract: AccountId,
+        amount: Balance,
+        wrapped_amount: Balance,
+    ) -> Promise {
+        ext_wrap_near::ft_transfer(
+            account_id.clone(),
+            (amount + wrapped_amount).into(),
+            None,
+            &token_contract,
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_wrap_near_self::resolve_claim_wrapped(
+            account_id,
+            token_contract,
+            amount.into(),
+            wrapped_amount.into(),
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_CLAIM_WRAPPED,
+        ))
+    }
+
+    fn fail_claim_wrapped(
+        &mut self,
+        account_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) {
+        self.restore_claim(account_id, amount);
+        emit_farm_event(
+            "near_claim_failed",
+            json!({
+                "account_id": account_id,
+                "receiver_id": account_id,
+                "amount": U128(amount),
+                "token_id": token_id,
+            }),
+        );
+    }
+
+    /// Keeps the wNEAR held by the farm owed to the account. The claimed NEAR stays claimed. If the
+    /// account has unregistered in the meantime, the wNEAR stays on the farm's wNEAR account.
+    fn owe_wrapped_near(&mut self, account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+        let owed = self.get_internal_account(account_id).1.is_some();
+        if owed {
+            let (account_id_hash, mut account) = self.get_mut_account(account_id);
+            account.wrapped_near_balance += amount;
+            self.save_account(&account_id_hash, &mut account);
+            self.total_wrapped_near_owed += amount;
+        }
+        emit_farm_event(
+            "wrapped_near_hold",
+            json!({
+                "account_id": account_id,
+                "amount": U128(amount),
+                "token_id": token_id,
+                "owed": owed,
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use mock_wrap_near::MockWrapNear;
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::test_utils::accounts;
+
+    /// Creates the farm with the mock wNEAR contract on `accounts(5)`, where the account 2 claims
+    /// 6 NEAR and the farm has deposited it into wNEAR.
+    fn setup_claim() -> (Farm, MockWrapNear) {
+        set_context(accounts(0), 0, 0);
+        let mut farm = Farm::new(accounts(1), accounts(0), None, None, None, None);
+        let mut wrap_near = MockWrapNear::new();
+        wrap_near.storage_deposit(None);
+        set_context(accounts(0), 0, 1);
+        farm.set_reward_stream_duration(0.into());
+        farm.set_wrap_near_account_id(Some(accounts(5)));
+        set_context(accounts(2), 0, ONE_NEAR);
+        farm.storage_deposit(None, None);
+        set_context(accounts(1), 0, 0);
+        farm.ft_on_transfer(
+            accounts(2),
+            ONE_NEAR.into(),
+            "\"DepositAndStake\"".to_string(),
+        );
+        set_context(accounts(4), 0, 6 * ONE_NEAR);
+        farm.take_my_near();
+
+        set_context(accounts(2), 0, 1);
+        farm.claim_near_wrapped(accounts(5));
+        set_context(accounts(0), 0, 6 * ONE_NEAR);
+        wrap_near.near_deposit();
+        set_callback_context(PromiseResult::Successful(vec![]));
+        farm.on_near_wrapped(
+            accounts(2).into(),
+            accounts(5).into(),
+            (6 * ONE_NEAR).into(),
+            0.into(),
+        );
+        (farm, wrap_near)
+    }
+
+    /// Fails the transfer of the claimed wNEAR to the account 2.
+    fn fail_transfer(farm: &mut Farm, wrap_near: &mut MockWrapNear) {
+        wrap_near.set_failures(false, true, false);
+        set_context(accounts(0), 0, 1);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            wrap_near.ft_transfer(accounts(2), (6 * ONE_NEAR).into(), None)
+        }));
+        assert!(result.is_err());
+        set_callback_context(PromiseResult::Failed);
+        let amount = farm.resolve_claim_wrapped(
+            accounts(2).into(),
+            accounts(5).into(),
+            (6 * ONE_NEAR).into(),
+            0.into(),
+        );
+        assert_eq!(amount.0, 0);
+        // The claim is rolled back only once the wNEAR is unwrapped.
+        let account = farm.get_account(accounts(2)).unwrap();
+        assert_eq!(account.near_balance.0, 0);
+    }
+
+    #[test]
+    fn test_failed_transfer_rollback() {
+        let (mut farm, mut wrap_near) = setup_claim();
+        fail_transfer(&mut farm, &mut wrap_near);
+
+        set_context(accounts(0), 0, 1);
+        wrap_near.near_withdraw((6 * ONE_NEAR).into());
+        set_callback_context(PromiseResult::Successful(vec![]));
+        farm.on_near_unwrapped(
+            accounts(2).into(),
+            accounts(5).into(),
+            (6 * ONE_NEAR).into(),
+        );
+        let account = farm.get_account(accounts(2)).unwrap();
+        assert_eq!(account.near_balance.0, 6 * ONE_NEAR);
+        assert_eq!(account.near_claimed.0, 0);
+        assert_eq!(account.wrapped_near_balance.0, 0);
+        assert_eq!(wrap_near.ft_balance_of(accounts(0)).0, 0);
+    }
+
+    #[test]
+    fn test_failed_withdraw_rollback() {
+        let (mut farm, mut wrap_near) = setup_claim();
+        fail_transfer(&mut farm, &mut wrap_near);
+
+        wrap_near.set_failures(false, false, true);
+        set_context(accounts(0), 0, 1);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            wrap_near.near_withdraw((6 * ONE_NEAR).into())
+        }));
+        assert!(result.is_err());
+        set_callback_context(PromiseResult::Failed);
+        farm.on_near_unwrapped(
+            accounts(2).into(),
+            accounts(5).into(),
+            (6 * ONE_NEAR).into(),
+        );
+        let account = farm.get_account(accounts(2)).unwrap();
+        assert_eq!(account.near_balance.0, 0);
+        assert_eq!(account.wrapped_near_balance.0, 6 * ONE_NEAR);
+        assert_eq!(farm.total_wrapped_near_owed, 6 * ONE_NEAR);
+
+        // The next wrapped claim sends the held wNEAR.
+        wrap_near.set_failures(false, false, false);
+        set_context(accounts(2), 0, 0);
+        wrap_near.storage_deposit(None);
+        set_context(accounts(2), 0, 1);
+        farm.claim_near_wrapped(accounts(5));
+        set_context(accounts(0), 0, 1);
+        wrap_near.ft_transfer(accounts(2), (6 * ONE_NEAR).into(), None);
+        set_callback_context(PromiseResult::Successful(vec![]));
+        let amount = farm.resolve_claim_wrapped(
+            accounts(2).into(),
+            accounts(5).into(),
+            0.into(),
+            (6 * ONE_NEAR).into(),
+        );
+        assert_eq!(amount.0, 6 * ONE_NEAR);
+        assert_eq!(wrap_near.ft_balance_of(accounts(2)).0, 6 * ONE_NEAR);
+        let account = farm.get_account(accounts(2)).unwrap();
+        assert_eq!(account.wrapped_near_balance.0, 0);
+        assert_eq!(farm.total_wrapped_near_owed, 0);
+    }
+}
@@ -0,0 +1,450 @@
+//! The factory of berry farms. The owner stores the berry farm code, and anyone can create a farm
+//! for their community on a sub-account of the factory. The farm is created, deployed and
+//! initialized in a single batch, so either all of it succeeds or the deposit is refunded.
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::UnorderedMap;
+use near_sdk::json_types::{
+    Base58CryptoHash, ValidAccountId, WrappedDuration, WrappedTimestamp, U128,
+};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{
+    assert_one_yocto, env, ext_contract, near_bindgen, AccountId, Balance, CryptoHash, Gas,
+    PanicOnDefault, Promise, PromiseResult, Timestamp,
+};
+use std::convert::TryInto;
+
+#[global_allocator]
+static ALLOC: near_sdk::wee_alloc::WeeAlloc<'_> = near_sdk::wee_alloc::WeeAlloc::INIT;
+
+const FARM_CODE_KEY: &[u8] = b"FARM_CODE";
+const FARMS_PREFIX: &[u8] = b"f";
+
+const NO_DEPOSIT: Balance = 0;
+
+/// Gas for `Farm::new`, including the registration with the seed token.
+const GAS_FOR_FARM_NEW: Gas = 50_000_000_000_000;
+const GAS_FOR_ON_FARM_CREATED: Gas = 10_000_000_000_000;
+
+/// The balance the farm needs besides the storage of its code, for the initial state.
+const FARM_STATE_DEPOSIT: Balance = 1_000_000_000_000_000_000_000_000;
+
+/// The decimals of the cucumbers minted by the farm, the metadata should match them.
+const CUCUMBER_DECIMALS: u8 = 18;
+
+/// The parameters of `Farm::new`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FarmConfig {
+    /// The token staked for cucumbers, the bananas of the farm.
+    pub seed_token_id: AccountId,
+    pub owner_id: AccountId,
+    pub unbonding_period: Option<WrappedDuration>,
+    pub instant_unstake_penalty_bps: Option<u16>,
+    pub upgrade_delay: Option<WrappedDuration>,
+    /// The metadata of the farm's cucumber token.
+    pub metadata: Option<FungibleTokenMetadata>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct FarmRecord {
+    pub config: FarmConfig,
+    pub creator_id: AccountId,
+    pub created_timestamp: Timestamp,
+    /// The sha256 hash of the deployed code.
+    pub code_hash: CryptoHash,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HumanFarm {
+    pub farm_id: AccountId,
+    pub config: FarmConfig,
+    pub creator_id: AccountId,
+    pub created_timestamp: WrappedTimestamp,
+    pub code_hash: Base58CryptoHash,
+}
+
+/// The farm passed from `create_farm` to `on_farm_created`.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingFarm {
+    pub config: FarmConfig,
+    pub creator_id: AccountId,
+    /// The hash of the deployed code. The stored code may change before the callback.
+    pub code_hash: Base58CryptoHash,
+    /// The whole attached deposit, refunded if the creation fails.
+    pub deposit: U128,
+}
+
+#[ext_contract(ext_self)]
+trait FarmFactoryResolver {
+    fn on_farm_created(&mut self, farm_id: AccountId, farm: PendingFarm) -> bool;
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct FarmFactory {
+    pub owner_id: AccountId,
+    /// The sha256 hash of the stored farm code.
+    pub farm_code_hash: Option<CryptoHash>,
+    pub farms: UnorderedMap<AccountId, FarmRecord>,
+}
+
+#[near_bindgen]
+impl FarmFactory {
+    #[init]
+    pub fn new(owner_id: ValidAccountId) -> Self {
+        Self {
+            owner_id: owner_id.into(),
+            farm_code_hash: None,
+            farms: UnorderedMap::new(FARMS_PREFIX.to_vec()),
+        }
+    }
+
+    /// Stores the berry farm code used for the new farms. The code is passed as the raw input of
+    /// the method. The existing farms are not affected.
+    pub fn store_farm_code(&mut self) -> Base58CryptoHash {
+        self.assert_owner();
+        let code = env::input().expect("The code should be passed as the input");
+        let code_hash: CryptoHash = env::sha256(&code).try_into().unwrap();
+        env::storage_write(FARM_CODE_KEY, &code);
+        self.farm_code_hash = Some(code_hash);
+        code_hash.into()
+    }
+
+    #[payable]
+    pub fn set_owner_id(&mut self, owner_id: ValidAccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.owner_id = owner_id.into();
+    }
+
+    /// Creates the farm on the sub-account `<name>.<factory>`, deploys the stored farm code and
+    /// calls `new` with the config. The attached deposit goes to the farm, except the storage cost
+    /// of the farm record kept by the factory. It should cover `get_required_deposit` together
+    /// with that cost.
+    /// The deposit is refunded if the farm can't be created, e.g. the account already exists.
+    #[payable]
+    pub fn create_farm(&mut self, name: String, config: FarmConfig) -> Promise {
+        let farm_id = format!("{}.{}", name, env::current_account_id());
+        if name.is_empty() || name.contains('.') || !env::is_valid_account_id(farm_id.as_bytes()) {
+            env::panic(b"The farm name is invalid");
+        }
+        if !env::is_valid_account_id(config.seed_token_id.as_bytes())
+            || !env::is_valid_account_id(config.owner_id.as_bytes())
+        {
+            env::panic(b"The farm config has an invalid account ID");
+        }
+        if let Some(metadata) = config.metadata.as_ref() {
+            metadata.assert_valid();
+            if metadata.decimals != CUCUMBER_DECIMALS {
+                env::panic(b"The cucumber metadata should have the cucumber decimals");
+            }
+        }
+        if self.farms.get(&farm_id).is_some() {
+            env::panic(b"The farm already exists");
+        }
+        let code = env::storage_read(FARM_CODE_KEY).expect("The farm code is not stored");
+        let code_hash = self.farm_code_hash.unwrap();
+        let creator_id = env::predecessor_account_id();
+        let record_storage_cost = self.record_storage_cost(
+            &farm_id,
+            &FarmRecord {
+                config: config.clone(),
+                creator_id: creator_id.clone(),
+                created_timestamp: env::block_timestamp(),
+                code_hash,
+            },
+        );
+        let deposit = env::attached_deposit();
+        if deposit < required_deposit(code.len()) + record_storage_cost {
+            env::panic(b"Not enough deposit to create the farm");
+        }
+        let args = json!({
+            "banana_token_account_id": config.seed_token_id,
+            "owner_id": config.owner_id,
+            "unbonding_period": config.unbonding_period,
+            "instant_unstake_penalty_bps": config.instant_unstake_penalty_bps,
+            "upgrade_delay": config.upgrade_delay,
+            "metadata": config.metadata,
+        })
+        .to_string()
+        .into_bytes();
+
+        Promise::new(farm_id.clone())
+            .create_account()
+            .transfer(deposit - record_storage_cost)
+            .deploy_contract(code)
+            .function_call(b"new".to_vec(), args, NO_DEPOSIT, GAS_FOR_FARM_NEW)
+            .then(ext_self::on_farm_created(
+                farm_id,
+                PendingFarm {
+                    config,
+                    creator_id,
+                    code_hash: code_hash.into(),
+                    deposit: deposit.into(),
+                },
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_ON_FARM_CREATED,
+            ))
+    }
+
+    /// Records the created farm, or refunds the deposit to the creator if the creation has
+    /// failed. Returns whether the farm was created.
+    #[private]
+    pub fn on_farm_created(&mut self, farm_id: AccountId, farm: PendingFarm) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => {
+                self.farms.insert(
+                    &farm_id,
+                    &FarmRecord {
+                        config: farm.config,
+                        creator_id: farm.creator_id,
+                        created_timestamp: env::block_timestamp(),
+                        code_hash: farm.code_hash.into(),
+                    },
+                );
+                env::log(format!("Created farm {}", farm_id).as_bytes());
+                true
+            }
+            PromiseResult::Failed => {
+                // The failed batch refunds the deposit to the factory.
+                Promise::new(farm.creator_id).transfer(farm.deposit.into());
+                false
+            }
+        }
+    }
+
+    pub fn get_owner_id(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    pub fn get_farm_code_hash(&self) -> Option<Base58CryptoHash> {
+        self.farm_code_hash.map(|code_hash| code_hash.into())
+    }
+
+    /// Returns the minimum deposit for `create_farm` with the stored code, without the storage
+    /// cost of the farm record, which depends on the config.
+    pub fn get_required_deposit(&self) -> U128 {
+        let code_len = env::storage_read(FARM_CODE_KEY)
+            .map(|code| code.len())
+            .unwrap_or(0);
+        required_deposit(code_len).into()
+    }
+
+    pub fn get_number_of_farms(&self) -> u64 {
+        self.farms.len()
+    }
+
+    pub fn get_farm(&self, farm_id: ValidAccountId) -> Option<HumanFarm> {
+        self.farms
+            .get(farm_id.as_ref())
+            .map(|record| human_farm(farm_id.into(), record))
+    }
+
+    /// Returns the deployed farms with their config, ordered by creation.
+    pub fn get_farms(&self, from_index: u64, limit: u64) -> Vec<HumanFarm> {
+        let keys = self.farms.keys_as_vector();
+        let values = self.farms.values_as_vector();
+        (from_index..std::cmp::min(from_index.saturating_add(limit), keys.len()))
+            .map(|index| human_farm(keys.get(index).unwrap(), values.get(index).unwrap()))
+            .collect()
+    }
+}
+
+impl FarmFactory {
+    fn assert_owner(&self) {
+        if env::predecessor_account_id() != self.owner_id {
+            env::panic(b"Only the owner can call this method");
+        }
+    }
+
+    /// Measures the storage cost of the new farm record by inserting and removing it.
+    fn record_storage_cost(&mut self, farm_id: &AccountId, record: &FarmRecord) -> Balance {
+        let initial_storage_usage = env::storage_usage();
+        self.farms.insert(farm_id, record);
+        let storage_usage = env::storage_usage() - initial_storage_usage;
+        self.farms.remove(farm_id);
+        storage_usage as Balance * env::storage_byte_cost()
+    }
+}
+
+fn required_deposit(code_len: usize) -> Balance {
+    code_len as Balance * env::storage_byte_cost() + FARM_STATE_DEPOSIT
+}
+
+fn human_farm(farm_id: AccountId, record: FarmRecord) -> HumanFarm {
+    HumanFarm {
+        farm_id,
+        config: record.config,
+        creator_id: record.creator_id,
+        created_timestamp: record.created_timestamp.into(),
+        code_hash: record.code_hash.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    const CODE: &[u8] = &[0; 1000];
+
+    /// Sets the context of a call to the factory on `accounts(0)`.
+    fn set_context(predecessor: ValidAccountId, deposit: Balance, input: &[u8]) {
+        let mut context = VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(predecessor)
+            .attached_deposit(deposit)
+            .build();
+        context.input = input.to_vec();
+        testing_env!(context);
+    }
+
+    fn set_callback_context(result: PromiseResult) {
+        let context = VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .build();
+        testing_env!(
+            context,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![result]
+        );
+    }
+
+    fn setup_factory() -> FarmFactory {
+        set_context(accounts(0), 0, &[]);
+        let mut factory = FarmFactory::new(accounts(0));
+        set_context(accounts(0), 0, CODE);
+        factory.store_farm_code();
+        factory
+    }
+
+    fn config() -> FarmConfig {
+        FarmConfig {
+            seed_token_id: accounts(1).into(),
+            owner_id: accounts(2).into(),
+            unbonding_period: None,
+            instant_unstake_penalty_bps: None,
+            upgrade_delay: None,
+            metadata: None,
+        }
+    }
+
+    fn pending_farm() -> PendingFarm {
+        PendingFarm {
+            config: config(),
+            creator_id: accounts(3).into(),
+            code_hash: [0; 32].into(),
+            deposit: 10.into(),
+        }
+    }
+
+    /// Returns the deposit covering the farm and the storage of its record.
+    fn create_farm_deposit(factory: &mut FarmFactory, name: &str) -> Balance {
+        let farm_id = format!("{}.{}", name, accounts(0));
+        let record_storage_cost = factory.record_storage_cost(
+            &farm_id,
+            &FarmRecord {
+                config: config(),
+                creator_id: accounts(3).into(),
+                created_timestamp: 0,
+                code_hash: factory.farm_code_hash.unwrap(),
+            },
+        );
+        assert!(record_storage_cost > 0);
+        assert!(factory.farms.get(&farm_id).is_none());
+        factory.get_required_deposit().0 + record_storage_cost
+    }
+
+    #[test]
+    fn test_create_farm() {
+        let mut factory = setup_factory();
+        let deposit = create_farm_deposit(&mut factory, "farm");
+        assert_eq!(
+            factory.get_required_deposit().0,
+            CODE.len() as Balance * env::storage_byte_cost() + FARM_STATE_DEPOSIT
+        );
+        set_context(accounts(3), deposit, &[]);
+        factory.create_farm("farm".to_string(), config());
+        assert_eq!(factory.get_number_of_farms(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough deposit to create the farm")]
+    fn test_create_farm_not_enough_deposit() {
+        let mut factory = setup_factory();
+        let deposit = create_farm_deposit(&mut factory, "farm");
+        set_context(accounts(3), deposit - 1, &[]);
+        factory.create_farm("farm".to_string(), config());
+    }
+
+    #[test]
+    #[should_panic(expected = "The cucumber metadata should have the cucumber decimals")]
+    fn test_create_farm_wrong_decimals() {
+        let mut factory = setup_factory();
+        let deposit = create_farm_deposit(&mut factory, "farm");
+        let mut config = config();
+        config.metadata = Some(FungibleTokenMetadata {
+            spec: "ft-1.0.0".to_string(),
+            name: "Cucumber".to_string(),
+            symbol: "CUCUMBER".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 24,
+        });
+        set_context(accounts(3), deposit, &[]);
+        factory.create_farm("farm".to_string(), config);
+    }
+
+    #[test]
+    fn test_on_farm_created() {
+        let mut factory = setup_factory();
+        set_callback_context(PromiseResult::Failed);
+        assert!(!factory.on_farm_created("farm.alice".to_string(), pending_farm()));
+        assert!(factory.get_farm(accounts(0)).is_none());
+        assert_eq!(factory.get_number_of_farms(), 0);
+        // The refund of the deposit to the creator.
+        assert_eq!(get_created_receipts().len(), 1);
+
+        set_callback_context(PromiseResult::Successful(vec![]));
+        assert!(factory.on_farm_created("farm.alice".to_string(), pending_farm()));
+        let farm = factory.get_farm("farm.alice".try_into().unwrap()).unwrap();
+        assert_eq!(farm.creator_id, accounts(3).to_string());
+        assert!(get_created_receipts().is_empty());
+    }
+
+    #[test]
+    fn test_get_farms() {
+        let mut factory = setup_factory();
+        for name in ["a", "b", "c"].iter() {
+            set_callback_context(PromiseResult::Successful(vec![]));
+            factory.on_farm_created(format!("{}.alice", name), pending_farm());
+        }
+        let farm_ids = |farms: Vec<HumanFarm>| -> Vec<AccountId> {
+            farms.into_iter().map(|farm| farm.farm_id).collect()
+        };
+        assert_eq!(farm_ids(factory.get_farms(1, 1)), vec!["b.alice"]);
+        assert_eq!(
+            farm_ids(factory.get_farms(1, u64::MAX)),
+            vec!["b.alice", "c.alice"]
+        );
+        assert!(factory.get_farms(5, 2).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_set_owner_id_without_yocto() {
+        let mut factory = setup_factory();
+        factory.set_owner_id(accounts(1));
+    }
+}
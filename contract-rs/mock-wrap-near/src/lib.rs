@@ -0,0 +1,114 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::{assert_one_yocto, env, near_bindgen, AccountId, Balance, PanicOnDefault, Promise};
+
+// Only in the contract, so the unit tests of the farm can link the mock.
+#[cfg(target_arch = "wasm32")]
+#[global_allocator]
+static ALLOC: near_sdk::wee_alloc::WeeAlloc<'_> = near_sdk::wee_alloc::WeeAlloc::INIT;
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct MockWrapNear {
+    pub balances: LookupMap<AccountId, Balance>,
+    pub fail_near_deposit: bool,
+    pub fail_ft_transfer: bool,
+    pub fail_near_withdraw: bool,
+}
+
+#[near_bindgen]
+impl MockWrapNear {
+    #[init]
+    pub fn new() -> Self {
+        Self {
+            balances: LookupMap::new(b"b".to_vec()),
+            fail_near_deposit: false,
+            fail_ft_transfer: false,
+            fail_near_withdraw: false,
+        }
+    }
+
+    /// Makes the following deposits, transfers or withdrawals fail.
+    pub fn set_failures(
+        &mut self,
+        fail_near_deposit: bool,
+        fail_ft_transfer: bool,
+        fail_near_withdraw: bool,
+    ) {
+        self.fail_near_deposit = fail_near_deposit;
+        self.fail_ft_transfer = fail_ft_transfer;
+        self.fail_near_withdraw = fail_near_withdraw;
+    }
+
+    /// Registers the account for free. The real contract requires a storage deposit.
+    #[payable]
+    pub fn storage_deposit(&mut self, account_id: Option<ValidAccountId>) {
+        let account_id = account_id
+            .map(|id| id.into())
+            .unwrap_or_else(env::predecessor_account_id);
+        if self.balances.get(&account_id).is_none() {
+            self.balances.insert(&account_id, &0);
+        }
+        if env::attached_deposit() > 0 {
+            Promise::new(env::predecessor_account_id()).transfer(env::attached_deposit());
+        }
+    }
+
+    #[payable]
+    pub fn near_deposit(&mut self) {
+        if self.fail_near_deposit {
+            env::panic(b"The deposit has failed");
+        }
+        let account_id = env::predecessor_account_id();
+        let balance = self.get_balance(&account_id);
+        self.balances
+            .insert(&account_id, &(balance + env::attached_deposit()));
+    }
+
+    #[payable]
+    pub fn near_withdraw(&mut self, amount: U128) -> Promise {
+        assert_one_yocto();
+        if self.fail_near_withdraw {
+            env::panic(b"The withdrawal has failed");
+        }
+        let account_id = env::predecessor_account_id();
+        let balance = self.get_balance(&account_id);
+        if balance < amount.0 {
+            env::panic(b"Not enough balance");
+        }
+        self.balances.insert(&account_id, &(balance - amount.0));
+        Promise::new(account_id).transfer(amount.0)
+    }
+
+    #[payable]
+    pub fn ft_transfer(&mut self, receiver_id: ValidAccountId, amount: U128, memo: Option<String>) {
+        assert_one_yocto();
+        let _ = memo;
+        if self.fail_ft_transfer {
+            env::panic(b"The transfer has failed");
+        }
+        let sender_id = env::predecessor_account_id();
+        let sender_balance = self.get_balance(&sender_id);
+        if sender_balance < amount.0 {
+            env::panic(b"Not enough balance");
+        }
+        let receiver_balance = self.get_balance(receiver_id.as_ref());
+        self.balances
+            .insert(&sender_id, &(sender_balance - amount.0));
+        self.balances
+            .insert(receiver_id.as_ref(), &(receiver_balance + amount.0));
+    }
+
+    pub fn ft_balance_of(&self, account_id: ValidAccountId) -> U128 {
+        self.balances.get(account_id.as_ref()).unwrap_or(0).into()
+    }
+}
+
+impl MockWrapNear {
+    fn get_balance(&self, account_id: &AccountId) -> Balance {
+        self.balances
+            .get(account_id)
+            .unwrap_or_else(|| env::panic(b"The account is not registered"))
+    }
+}
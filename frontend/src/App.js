@@ -180,8 +180,8 @@ class App extends React.Component {
       changeMethods: ['transfer_with_vault', 'ft_transfer_call'],
     });
     this._contract = new nearAPI.Contract(this._account, NearConfig.contractName, {
-      viewMethods: ['account_exists', 'get_account', 'get_stats', 'get_near_balance', 'get_total_near_claimed', 'get_total_near_received', 'get_balance', 'get_total_supply'],
-      changeMethods: ['claim_near', 'transfer_raw'],
+      viewMethods: ['account_exists', 'get_account', 'get_stats', 'get_near_balance', 'get_total_near_claimed', 'get_total_near_received', 'get_balance', 'get_total_supply', 'storage_balance_of', 'storage_balance_bounds'],
+      changeMethods: ['claim_near', 'transfer_raw', 'storage_deposit'],
     });
     this._pixelCostBN = new BN(await this._bananaContract.get_pixel_cost());
     this._pixelCost = parseFloat(this._pixelCostBN.toString());
@@ -212,6 +212,12 @@ class App extends React.Component {
     } else {
       bananas = this.state.account.bananaBalanceBN;
     }
+    // The farm only accepts the bananas of the registered accounts.
+    const storageBalance = await this._contract.storage_balance_of({ account_id: this._accountId });
+    if (!storageBalance) {
+      const storageBalanceBounds = await this._contract.storage_balance_bounds();
+      await this._contract.storage_deposit({}, new BN("30000000000000"), new BN(storageBalanceBounds.min))
+    }
     await this._bananaContract.ft_transfer_call({
       receiver_id: NearConfig.contractName,
       amount: bananas.toString(),